@@ -0,0 +1,80 @@
+//! Parse and search benchmarks over a generated fixture, so they run without
+//! the real EIJIRO file: `cargo bench -p eijiro-parser`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+/// Headwords in the fixture.
+const WORDS: usize = 20_000;
+
+/// Builds lines shaped like the real source: idents, complements, markers
+/// and examples, spread over `WORDS` made-up headwords.
+fn fixture() -> String {
+    let syllables = ["ka", "ri", "to", "me", "su", "no", "ra", "ve", "lo", "pi"];
+    let mut text = String::new();
+    for i in 0..WORDS {
+        let mut word = String::new();
+        let mut n = i;
+        loop {
+            word.push_str(syllables[n % syllables.len()]);
+            n /= syllables.len();
+            if n == 0 {
+                break;
+            }
+        }
+        text += &format!("■{} : 【レベル】{}、{}の意味\n", word, i % 12 + 1, word);
+        text += &format!(
+            "■{} {{動-{}}} : ①走る②逃げる◆【発音】wɜːd{}■・He {}s fast. : 彼は速く走る。\n",
+            word,
+            i % 3,
+            i,
+            word
+        );
+        text += &format!(
+            "■{} {{名}} : 〔機械の〕{}、{}◆【カナ】ワード\n",
+            word,
+            i,
+            i + 1
+        );
+    }
+    text
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let text = fixture();
+    c.bench_function("parse", |b| {
+        b.iter(|| eijiro_parser::parse(black_box(&text)).unwrap())
+    });
+}
+
+fn bench_search(c: &mut Criterion) {
+    let dict = eijiro_parser::parse(&fixture()).unwrap();
+    // "karito" exists; "karitx" is one edit away from it.
+    c.bench_function("get", |b| b.iter(|| dict.get(black_box("karito"))));
+    c.bench_function("search/exact", |b| {
+        b.iter(|| eijiro_parser::search(&dict, black_box("karito"), 0).unwrap())
+    });
+    c.bench_function("search/prefix", |b| {
+        b.iter(|| eijiro_parser::prefix_search(&dict, black_box("kari")).unwrap())
+    });
+    c.bench_function("search/distance-1", |b| {
+        b.iter(|| eijiro_parser::search(&dict, black_box("karitx"), 1).unwrap())
+    });
+    c.bench_function("search/distance-2", |b| {
+        b.iter(|| eijiro_parser::search(&dict, black_box("karitx"), 2).unwrap())
+    });
+}
+
+/// Construction alone, to compare with the searches above.
+fn bench_levenshtein(c: &mut Criterion) {
+    for distance in 1..=2 {
+        c.bench_function(&format!("levenshtein/build-{}", distance), |b| {
+            b.iter(|| {
+                eijiro_parser::fst::automaton::Levenshtein::new(black_box("karitx"), distance)
+                    .unwrap()
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_parse, bench_search, bench_levenshtein);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+//! Searches a dictionary rebuilt from in-memory buffers, the way a WASM
+//! frontend would after fetching `keys.fst`, `folded.fst` and
+//! `fields.bincode`. Builds with `--no-default-features`.
+
+use eijiro_parser::Dict;
+
+fn main() -> anyhow::Result<()> {
+    let source = "■run {動} : 走る、駆ける\n■runner {名} : 走者\n■walk {動} : 歩く";
+    let bytes = eijiro_parser::parse(source)?.to_bytes()?;
+
+    let dict = Dict::from_bytes(&bytes.keys, &bytes.folded, &bytes.fields)?;
+    for (word, fields) in eijiro_parser::search(&dict, "rum", 1)? {
+        for f in fields {
+            println!("{} : {}", word, f.explanation.body);
+        }
+    }
+    Ok(())
+}
@@ -1,35 +1,174 @@
 use anyhow::{anyhow, Result};
-use fst::{Map, MapBuilder};
+use fst::{Automaton, IntoStreamer, Map, MapBuilder, Streamer};
 use lazy_static::lazy_static;
+use log::warn;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use regex::Regex;
 use serde::de::{Deserializer, SeqAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::io::BufRead;
+#[cfg(feature = "fs")]
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 pub extern crate fst;
 
+mod store;
+pub use store::{Fields, Groups, Phrases, Reverse, Storage};
+
+/// Bumped whenever the serialized layout of `Dict` changes.
+pub const FORMAT_VERSION: u32 = 15;
+
 #[derive(Debug, Clone)]
 pub struct Dict {
-    pub keys: Map<Vec<u8>>,
-    pub fields: Vec<Vec<Field>>,
+    pub keys: Map<Storage>,
+    pub fields: Fields,
+    /// Japanese explanation token -> fields containing it.
+    pub reverse: Reverse,
+    /// Lowercased headwords, for case-insensitive lookup.
+    pub folded: FoldedKeys,
+    /// Spaced or hyphenated headwords by `canonical_phrase`.
+    pub phrases: Phrases,
+    /// The source file this dictionary was parsed from, if any.
+    pub source: Option<SourceStamp>,
+}
+
+/// Modification time and size of a source file, kept with a cached
+/// dictionary to tell whether the source has changed since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceStamp {
+    /// Nanoseconds since the Unix epoch.
+    pub modified: u64,
+    pub len: u64,
+}
+
+impl SourceStamp {
+    #[cfg(feature = "fs")]
+    pub fn of(path: &Path) -> std::io::Result<SourceStamp> {
+        let meta = std::fs::metadata(path)?;
+        let modified = meta
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Ok(SourceStamp {
+            modified,
+            len: meta.len(),
+        })
+    }
+}
+
+/// Secondary key index from lowercased headwords back to the original
+/// headwords in `Dict::keys`.
+#[derive(Debug, Clone)]
+pub struct FoldedKeys {
+    /// Lowercased headword -> index into `groups`.
+    pub keys: Map<Storage>,
+    /// Original headwords per folded key; `None` stands for the folded key
+    /// itself, which keeps the common all-lowercase case from being copied.
+    pub groups: Groups,
 }
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone)]
+
+impl FoldedKeys {
+    pub fn build<D: AsRef<[u8]>>(keys: &Map<D>) -> Result<FoldedKeys> {
+        let mut folded = Vec::with_capacity(keys.len());
+        let mut stream = keys.stream();
+        while let Some((k, _)) = stream.next() {
+            let original = String::from_utf8_lossy(k).into_owned();
+            folded.push((original.to_lowercase(), original));
+        }
+        folded.sort();
+
+        let mut map = MapBuilder::memory();
+        let mut groups: Vec<Vec<Option<String>>> = Vec::new();
+        let mut prev_key: Option<String> = None;
+        for (k, original) in folded {
+            if prev_key.as_ref() != Some(&k) {
+                map.insert(&k, groups.len() as u64)?;
+                groups.push(Vec::new());
+            }
+            let variant = if original == k { None } else { Some(original) };
+            groups.last_mut().unwrap().push(variant);
+            prev_key = Some(k);
+        }
+        let keys = Map::new(Storage::Owned(map.into_inner()?))?;
+        Ok(FoldedKeys {
+            keys,
+            groups: Groups::from(groups),
+        })
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Clone)]
+pub struct ReverseEntry {
+    pub headword: String,
+    /// Index into `Dict::fields`.
+    pub idx: u32,
+    /// Position of the field within `Dict::fields[idx]`.
+    pub field: u32,
+}
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Clone)]
 pub struct Field {
     pub ident: Option<String>,
+    /// Structured form of `ident`.
+    pub pos: Option<PartOfSpeech>,
+    /// Difficulty from a 【レベル】 marker, removed from the explanation.
+    pub level: Option<u8>,
+    /// Frequency rank from a 【ランク】/【頻度】 marker (1 is the most
+    /// common), removed from the explanation. Eijiro itself seldom has one;
+    /// word lists added with it can.
+    pub rank: Option<u32>,
     pub explanation: Explanation,
     pub examples: Vec<Example>,
+    /// Headwords referenced with `→` (or `⇒`, `￫`, `->`), removed from
+    /// the explanation.
+    pub see_also: Vec<String>,
+    /// The source line exactly as read, with `ParseOptions::keep_raw`.
+    pub raw: Option<String>,
+}
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Clone)]
+pub enum PartOfSpeech {
+    Noun,
+    Pronoun,
+    Verb,
+    AuxiliaryVerb,
+    PhrasalVerb,
+    Adjective,
+    Adverb,
+    Preposition,
+    Conjunction,
+    Interjection,
+    Article,
+    Abbreviation,
+    Prefix,
+    Suffix,
+    Other(String),
 }
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Clone)]
 pub struct Explanation {
     pub body: String,
     pub complements: Vec<Complement>,
+    /// Value of a 【発音】/【音声】 marker, removed from `body`/`complements`.
+    pub pronunciation: Option<String>,
+    /// `body` split at its sense numbers (see `split_senses`).
+    pub senses: Vec<String>,
 }
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Clone)]
 pub struct Example {
     pub sentence: String,
+    /// English half of `sentence`, without the leading `・`.
+    pub english: Option<String>,
+    /// Japanese translation following the English half.
+    pub japanese: Option<String>,
     pub complements: Vec<Complement>,
 }
-#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Clone)]
 pub struct Complement {
     pub body: String,
 }
@@ -39,10 +178,15 @@ impl Serialize for Dict {
     where
         S: Serializer,
     {
-        let mut seq = serializer.serialize_struct("Dict", 2)?;
-        let keys_bytes = self.keys.clone().into_fst().into_inner();
-        seq.serialize_field("keys", &keys_bytes)?;
+        let mut seq = serializer.serialize_struct("Dict", 8)?;
+        seq.serialize_field("version", &FORMAT_VERSION)?;
+        seq.serialize_field("keys", self.keys.as_fst().as_bytes())?;
         seq.serialize_field("fields", &self.fields)?;
+        seq.serialize_field("reverse", &self.reverse)?;
+        seq.serialize_field("folded_keys", self.folded.keys.as_fst().as_bytes())?;
+        seq.serialize_field("folded_groups", &self.folded.groups)?;
+        seq.serialize_field("phrases", &self.phrases)?;
+        seq.serialize_field("source", &self.source)?;
         seq.end()
     }
 }
@@ -63,17 +207,551 @@ impl<'de> Deserialize<'de> for Dict {
                 V: SeqAccess<'de>,
             {
                 use serde::de::Error as de_err;
-                let keys_bytes = seq
+                let version: u32 = seq
                     .next_element()?
                     .ok_or_else(|| de_err::invalid_length(0, &self))?;
-                let keys = Map::new(keys_bytes).unwrap();
-                let fields = seq
+                if version != FORMAT_VERSION {
+                    return Err(de_err::custom(format!(
+                        "unsupported dict format version {} (expected {})",
+                        version, FORMAT_VERSION
+                    )));
+                }
+                let keys_bytes = seq
                     .next_element()?
                     .ok_or_else(|| de_err::invalid_length(1, &self))?;
-                Ok(Dict { keys, fields })
+                let keys = Map::new(Storage::Owned(keys_bytes)).map_err(de_err::custom)?;
+                let fields = seq
+                    .next_element()?
+                    .ok_or_else(|| de_err::invalid_length(2, &self))?;
+                let reverse = seq
+                    .next_element()?
+                    .ok_or_else(|| de_err::invalid_length(3, &self))?;
+                let folded_bytes = seq
+                    .next_element()?
+                    .ok_or_else(|| de_err::invalid_length(4, &self))?;
+                let folded_keys = Map::new(Storage::Owned(folded_bytes)).map_err(de_err::custom)?;
+                let groups = seq
+                    .next_element()?
+                    .ok_or_else(|| de_err::invalid_length(5, &self))?;
+                let folded = FoldedKeys {
+                    keys: folded_keys,
+                    groups,
+                };
+                let phrases = seq
+                    .next_element()?
+                    .ok_or_else(|| de_err::invalid_length(6, &self))?;
+                let source = seq
+                    .next_element()?
+                    .ok_or_else(|| de_err::invalid_length(7, &self))?;
+                Ok(Dict {
+                    keys,
+                    fields,
+                    reverse,
+                    folded,
+                    phrases,
+                    source,
+                })
+            }
+        }
+        deserializer.deserialize_struct(
+            "Dict",
+            &[
+                "version",
+                "keys",
+                "fields",
+                "reverse",
+                "folded_keys",
+                "folded_groups",
+                "phrases",
+                "source",
+            ],
+            DictVisitor,
+        )
+    }
+}
+
+impl PartOfSpeech {
+    /// Parses the text between the braces of an entry, e.g. `名`, `他動-1` or `名-複`.
+    /// Sense numbers and suffixes after `-` are ignored.
+    pub fn from_ident(ident: &str) -> PartOfSpeech {
+        use PartOfSpeech::*;
+        let tag = ident.split('-').next().unwrap_or("").trim();
+        match tag {
+            "名" => Noun,
+            "代" | "代名" => Pronoun,
+            "動" | "自動" | "他動" => Verb,
+            "助動" => AuxiliaryVerb,
+            "句動" => PhrasalVerb,
+            "形" => Adjective,
+            "副" => Adverb,
+            "前" => Preposition,
+            "接" => Conjunction,
+            "間" => Interjection,
+            "冠" => Article,
+            "略" => Abbreviation,
+            "接頭" => Prefix,
+            "接尾" => Suffix,
+            _ => Other(ident.to_string()),
+        }
+    }
+}
+
+/// Serialized dictionary parts, as written to disk by `Dict::save`.
+#[derive(Debug, Clone)]
+pub struct DictBytes {
+    /// Contents of `KEYS_FILE`.
+    pub keys: Vec<u8>,
+    /// Contents of `FOLDED_FILE`.
+    pub folded: Vec<u8>,
+    /// Contents of `FIELDS_FILE`.
+    pub fields: Vec<u8>,
+}
+
+/// Estimated memory held by a loaded dictionary, from `Dict::memory_usage`.
+/// All sizes are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MemoryUsage {
+    /// Bytes of the headword FST.
+    pub keys: usize,
+    /// Bytes of the lowercased key FST.
+    pub folded_keys: usize,
+    /// Heap estimate of the decoded fields: string lengths and vector
+    /// capacities. Fields of a store are only counted once decoded.
+    pub fields: usize,
+    /// Bytes of the fields store the fields are decoded from, if any.
+    pub fields_store: usize,
+    /// Whether the FSTs and the store are mapped from the cache files
+    /// (`Dict::load`) rather than held in memory.
+    pub mapped: bool,
+}
+
+/// FST file inside a directory written by `Dict::save`.
+pub const KEYS_FILE: &str = "keys.fst";
+/// Fields store inside a directory written by `Dict::save`.
+pub const FIELDS_FILE: &str = "fields.bincode";
+/// Lowercased key FST (`FoldedKeys::keys`) inside a directory written by `Dict::save`.
+pub const FOLDED_FILE: &str = "folded.fst";
+
+/// Writes `path` through a temporary file in the same directory that is
+/// renamed over it, so a process that has the old file mapped keeps reading
+/// the old contents instead of a truncated file.
+#[cfg(feature = "fs")]
+pub fn replace_file(
+    path: &Path,
+    write: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let temp = write_temp(path, write)?;
+    std::fs::rename(&temp, path).map_err(|e| {
+        let _ = std::fs::remove_file(&temp);
+        e.into()
+    })
+}
+
+/// Writes a sibling of `path` named `.<name>.<pid>.tmp` and returns its
+/// path; removed again if `write` fails.
+#[cfg(feature = "fs")]
+fn write_temp(
+    path: &Path,
+    write: &mut dyn FnMut(&mut dyn Write) -> Result<()>,
+) -> Result<std::path::PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("not a file path: {}", path.display()))?;
+    let temp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let result = (|| {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&temp)?);
+        write(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => Ok(temp),
+        Err(e) => {
+            let _ = std::fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+fn is_japanese(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{30ff}' // CJK punctuation, hiragana, katakana
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ff00}'..='\u{ffef}' // full-width forms
+    )
+}
+
+impl Example {
+    /// Builds an example, splitting `sentence` at the first Japanese character
+    /// into its English and Japanese halves.
+    pub fn new(sentence: String, complements: Vec<Complement>) -> Example {
+        let text = sentence.trim_start_matches('・');
+        let (english, japanese) = match text.find(is_japanese) {
+            Some(i) => text.split_at(i),
+            None => (text, ""),
+        };
+        let non_empty = |s: &str| Some(s.trim().to_string()).filter(|s| !s.is_empty());
+        Example {
+            english: non_empty(english),
+            japanese: non_empty(japanese),
+            sentence,
+            complements,
+        }
+    }
+}
+
+/// The body followed by its `◆` complements, as in the source.
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.body)?;
+        for c in &self.complements {
+            write!(f, "◆{}", c.body)?;
+        }
+        Ok(())
+    }
+}
+
+/// `{ident} : ` and the explanation, then the pronunciation and each
+/// example half on indented lines below.
+impl std::fmt::Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(ident) = &self.ident {
+            write!(f, "{{{}}} : ", ident)?;
+        }
+        write!(f, "{}", self.explanation)?;
+        if let Some(p) = &self.explanation.pronunciation {
+            write!(f, "\n    【発音】{}", p)?;
+        }
+        if !self.see_also.is_empty() {
+            write!(f, "\n    → {}", self.see_also.join(", "))?;
+        }
+        for e in &self.examples {
+            match (&e.english, &e.japanese) {
+                (None, None) => write!(f, "\n        {}", e.sentence)?,
+                (english, japanese) => {
+                    for line in english.iter().chain(japanese.iter()) {
+                        write!(f, "\n        {}", line)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Field {
+    /// Estimated heap bytes owned by this field: the capacities of its
+    /// strings and vectors, not counting `size_of::<Field>()` itself.
+    pub fn heap_size(&self) -> usize {
+        fn option(s: &Option<String>) -> usize {
+            s.as_ref().map_or(0, |s| s.capacity())
+        }
+        fn complements(c: &Vec<Complement>) -> usize {
+            c.capacity() * std::mem::size_of::<Complement>()
+                + c.iter().map(|c| c.body.capacity()).sum::<usize>()
+        }
+        let e = &self.explanation;
+        option(&self.ident)
+            + e.body.capacity()
+            + complements(&e.complements)
+            + option(&e.pronunciation)
+            + e.senses.capacity() * std::mem::size_of::<String>()
+            + e.senses.iter().map(String::capacity).sum::<usize>()
+            + self.examples.capacity() * std::mem::size_of::<Example>()
+            + self
+                .examples
+                .iter()
+                .map(|x| {
+                    x.sentence.capacity()
+                        + option(&x.english)
+                        + option(&x.japanese)
+                        + complements(&x.complements)
+                })
+                .sum::<usize>()
+            + self.see_also.capacity() * std::mem::size_of::<String>()
+            + self.see_also.iter().map(String::capacity).sum::<usize>()
+            + option(&self.raw)
+    }
+
+    /// The line that introduces this field under `headword`, as it appears
+    /// in the source: `■run {動}`.
+    pub fn header(&self, headword: &str) -> String {
+        match &self.ident {
+            Some(ident) => format!("■{} {{{}}}", headword, ident),
+            None => format!("■{}", headword),
+        }
+    }
+}
+
+/// Iterator over every `(headword, fields)` pair of a `Dict`, in key order.
+/// Returned by `Dict::entries`.
+pub struct Entries<'a> {
+    stream: fst::map::Stream<'a>,
+    fields: &'a Fields,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    /// The FST stream reuses its key buffer, so headwords are owned.
+    type Item = (String, &'a [Field]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (k, idx) = self.stream.next()?;
+            let headword = String::from_utf8_lossy(k).into_owned();
+            match self.fields.get(idx as usize) {
+                Some(fields) => return Some((headword, fields.as_slice())),
+                None => warn_missing_fields(&headword, idx),
             }
         }
-        deserializer.deserialize_struct("Dict", &["keys", "fields"], DictVisitor)
+    }
+}
+
+impl Dict {
+    /// Whether `word` is a headword. Only walks the key FST, so `fields`
+    /// is never touched.
+    pub fn contains(&self, word: &str) -> bool {
+        self.keys.contains_key(word)
+    }
+
+    /// Exact headword lookup, without building an automaton.
+    pub fn get(&self, word: &str) -> Option<&[Field]> {
+        let idx = self.keys.get(word)?;
+        self.fields_for(idx).map(Vec::as_slice)
+    }
+
+    /// Builds a dictionary in memory from `(headword, fields)` pairs in any
+    /// order, e.g. for tests or a small custom word list. A headword given
+    /// twice is an error; see `new_from_entries_with` to merge instead.
+    pub fn new_from_entries<I>(entries: I) -> Result<Dict>
+    where
+        I: IntoIterator<Item = (String, Vec<Field>)>,
+    {
+        Dict::new_from_entries_with(entries, DuplicateKeys::Error)
+    }
+
+    /// `new_from_entries` with a policy for repeated headwords.
+    pub fn new_from_entries_with<I>(entries: I, duplicates: DuplicateKeys) -> Result<Dict>
+    where
+        I: IntoIterator<Item = (String, Vec<Field>)>,
+    {
+        let mut entries = entries.into_iter().collect::<Vec<_>>();
+        // Stable, so merged fields keep the order they were given in.
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut map = MapBuilder::memory();
+        let mut prev_key: Option<String> = None;
+        let mut fields: Vec<Vec<Field>> = Vec::new();
+        for (k, entry) in entries {
+            if prev_key.as_ref() == Some(&k) {
+                if duplicates == DuplicateKeys::Error {
+                    return Err(anyhow!("duplicate headword: {}", k));
+                }
+                let current = fields.last_mut().unwrap();
+                for f in entry {
+                    if !current.contains(&f) {
+                        current.push(f);
+                    }
+                }
+                continue;
+            }
+            map.insert(&k, fields.len() as u64)?;
+            fields.push(entry);
+            prev_key = Some(k);
+        }
+        assemble(map.into_map(), fields, None)
+    }
+
+    /// Resolves a permalink made by `field_id`, e.g. `apple#2`.
+    pub fn get_by_id<'a>(&'a self, id: &'a str) -> Option<(&'a str, &'a Field)> {
+        let (headword, n) = id.rsplit_once('#')?;
+        let position = n.parse::<usize>().ok()?.checked_sub(1)?;
+        Some((headword, self.get(headword)?.get(position)?))
+    }
+
+    /// Where `field` sits among the fields of `headword`, for `field_id`.
+    pub fn field_position(&self, headword: &str, field: &Field) -> Option<usize> {
+        self.get(headword)?.iter().position(|f| f == field)
+    }
+
+    /// Adds the entries of `other`, e.g. a few lines parsed from a personal
+    /// word list. Headwords in both keep this dictionary's fields first,
+    /// followed by those of `other` not already present. The indexes are
+    /// rebuilt in memory, so the result no longer borrows any mapped files.
+    pub fn merge(&mut self, other: Dict) -> Result<()> {
+        let mut map = MapBuilder::memory();
+        let mut fields: Vec<Vec<Field>> = Vec::new();
+        let mut union = fst::map::OpBuilder::new()
+            .add(&self.keys)
+            .add(&other.keys)
+            .union();
+        while let Some((k, values)) = union.next() {
+            let mut merged = Vec::new();
+            // `values` is ordered by the index of the map it came from.
+            for v in values {
+                let dict = if v.index == 0 { &*self } else { &other };
+                for f in dict.fields_for(v.value).into_iter().flatten() {
+                    if !merged.contains(f) {
+                        merged.push(f.clone());
+                    }
+                }
+            }
+            map.insert(k, fields.len() as u64)?;
+            fields.push(merged);
+        }
+        drop(union);
+        *self = assemble(map.into_map(), fields, self.source)?;
+        Ok(())
+    }
+
+    /// The fields for a value stored in `keys`, or `None` if the FST and
+    /// `fields` are out of sync (e.g. a partially written cache).
+    pub fn fields_for(&self, idx: u64) -> Option<&Vec<Field>> {
+        self.fields.get(usize::try_from(idx).ok()?)
+    }
+
+    /// Walks the FST lazily; nothing is collected up front.
+    pub fn entries(&self) -> Entries<'_> {
+        Entries {
+            stream: self.keys.stream(),
+            fields: &self.fields,
+        }
+    }
+
+    /// Estimates the memory held by the FSTs and the fields.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let store = self.fields.store();
+        MemoryUsage {
+            keys: self.keys.as_fst().as_bytes().len(),
+            folded_keys: self.folded.keys.as_fst().as_bytes().len(),
+            fields: self.fields.heap_size(),
+            fields_store: store.map_or(0, |s| s.as_ref().len()),
+            mapped: store.is_some_and(Storage::is_mapped),
+        }
+    }
+
+    /// Writes the FSTs and the fields store into `dir` as `KEYS_FILE`,
+    /// `FOLDED_FILE` and `FIELDS_FILE`, so none is rebuilt on load.
+    ///
+    /// Other processes may have the previous files mapped (`Dict::load`), so
+    /// they are never truncated: all three are written to temporary files
+    /// next to them first and then renamed into place.
+    #[cfg(feature = "fs")]
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let mut written = Vec::new();
+        let result = (|| {
+            let keys = write_temp(&dir.join(KEYS_FILE), &mut |out| {
+                Ok(out.write_all(self.keys.as_fst().as_bytes())?)
+            })?;
+            written.push((keys, KEYS_FILE));
+            let folded = write_temp(&dir.join(FOLDED_FILE), &mut |out| {
+                Ok(out.write_all(self.folded.keys.as_fst().as_bytes())?)
+            })?;
+            written.push((folded, FOLDED_FILE));
+            let fields = write_temp(&dir.join(FIELDS_FILE), &mut |out| {
+                store::write_store(
+                    out,
+                    &self.fields,
+                    &self.reverse,
+                    &self.folded.groups,
+                    &self.phrases,
+                    self.source,
+                )
+            })?;
+            written.push((fields, FIELDS_FILE));
+            Ok(())
+        })();
+        if let Err(e) = result {
+            for (temp, _) in &written {
+                let _ = std::fs::remove_file(temp);
+            }
+            return Err(e);
+        }
+        // The store goes last: it is what `load` checks the version of.
+        for (temp, name) in written {
+            std::fs::rename(&temp, dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Memory-maps a dictionary written by `Dict::save`.
+    /// Fields are decoded lazily as they are looked up.
+    #[cfg(feature = "fs")]
+    pub fn load(dir: &Path) -> Result<Dict> {
+        let (keys_path, fields_path) = (dir.join(KEYS_FILE), dir.join(FIELDS_FILE));
+        let dict = Dict::from_storage(
+            store::map_file(&keys_path)?,
+            store::map_file(&dir.join(FOLDED_FILE))?,
+            store::map_file(&fields_path)?,
+        )?;
+        if dict.keys.len() != dict.fields.len() {
+            return Err(anyhow!(
+                "{} has {} keys but {} has {} entries",
+                keys_path.display(),
+                dict.keys.len(),
+                fields_path.display(),
+                dict.fields.len()
+            ));
+        }
+        Ok(dict)
+    }
+
+    /// The contents of the files `Dict::save` writes.
+    pub fn to_bytes(&self) -> Result<DictBytes> {
+        let mut fields = Vec::new();
+        store::write_store(
+            &mut fields,
+            &self.fields,
+            &self.reverse,
+            &self.folded.groups,
+            &self.phrases,
+            self.source,
+        )?;
+        Ok(DictBytes {
+            keys: self.keys.as_fst().as_bytes().to_vec(),
+            folded: self.folded.keys.as_fst().as_bytes().to_vec(),
+            fields,
+        })
+    }
+
+    /// Builds a dictionary from the contents of `KEYS_FILE`, `FOLDED_FILE`
+    /// and `FIELDS_FILE` without touching the filesystem, e.g. from bytes
+    /// fetched by a web frontend. Fields are still decoded lazily.
+    pub fn from_bytes(keys: &[u8], folded: &[u8], fields: &[u8]) -> Result<Dict> {
+        let dict = Dict::from_storage(
+            Storage::Owned(keys.to_vec()),
+            Storage::Owned(folded.to_vec()),
+            Storage::Owned(fields.to_vec()),
+        )?;
+        if dict.keys.len() != dict.fields.len() {
+            return Err(anyhow!(
+                "FST has {} keys but the fields store has {} entries",
+                dict.keys.len(),
+                dict.fields.len()
+            ));
+        }
+        Ok(dict)
+    }
+
+    fn from_storage(keys: Storage, folded: Storage, fields: Storage) -> Result<Dict> {
+        let keys = Map::new(keys)?;
+        let folded_keys = Map::new(folded)?;
+        let stored = store::open_store(fields)?;
+        Ok(Dict {
+            keys,
+            fields: stored.fields,
+            reverse: stored.reverse,
+            folded: FoldedKeys {
+                keys: folded_keys,
+                groups: stored.groups,
+            },
+            phrases: stored.phrases,
+            source: stored.source,
+        })
     }
 }
 
@@ -102,73 +780,1076 @@ fn parse_examples(text: &str) -> Result<Vec<Example>> {
     EXAMPLE
         .captures_iter(text)
         .map(|m| {
-            Ok(Example {
-                sentence: m
-                    .get(1)
+            Ok(Example::new(
+                m.get(1)
                     .ok_or(anyhow!("Invalid example format"))?
                     .as_str()
                     .to_string(),
-                complements: m
-                    .name("complements")
+                m.name("complements")
                     .map(|m| parse_complements(m.as_str()))
                     .unwrap_or(Ok(Vec::new()))?,
-            })
+            ))
         })
         .collect()
 }
 
+/// Removes the first `marker` match from `text`, returning the stripped text
+/// and the marker's `value` group.
+fn split_marker(text: &str, marker: &Regex) -> Option<(String, String)> {
+    let cap = marker.captures(text)?;
+    let value = cap["value"].trim().to_string();
+    let whole = cap.get(0).unwrap();
+    let rest = format!("{}{}", &text[..whole.start()], &text[whole.end()..]);
+    let rest = rest.trim().trim_start_matches(['、', '。']);
+    Some((rest.trim_end_matches('、').to_string(), value))
+}
+
+/// Takes a marker out of the body or, failing that, out of the first
+/// complement containing it. Complements left empty are dropped.
+fn take_marker(explanation: &mut Explanation, marker: &Regex) -> Option<String> {
+    if let Some((body, value)) = split_marker(&explanation.body, marker) {
+        explanation.body = body;
+        return Some(value);
+    }
+    for (i, c) in explanation.complements.iter_mut().enumerate() {
+        if let Some((body, value)) = split_marker(&c.body, marker) {
+            if body.is_empty() {
+                explanation.complements.remove(i);
+            } else {
+                c.body = body;
+            }
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Trims `text` and collapses runs of ASCII spaces and tabs into one space.
+/// Only used on explanations; example sentences are kept as written.
+fn collapse_spaces(text: &str) -> String {
+    text.split_ascii_whitespace().collect::<Vec<_>>().join(" ")
+}
+
 fn parse_field(text: &str) -> Result<(String, Field)> {
     lazy_static! {
         static ref RE: Regex = Regex::new(
             r#"■(?P<item>.+?)(?: +\{(?P<ident>.+)\})? : (?P<exp>[^◆■]*)(?P<complements>(?:◆[^◆■]+)*)(?P<examples>(■.+)*)"#
         )
         .unwrap();
+        static ref PRONUNCIATION: Regex =
+            Regex::new(r#"【(?:発音|音声)[!！]?】(?P<value>[^【、]*)、?"#).unwrap();
+        static ref LEVEL: Regex = Regex::new(r#"【レベル】\s*(?P<value>\d+)、?"#).unwrap();
+        static ref RANK: Regex =
+            Regex::new(r#"【(?:ランク|頻度)】\s*(?P<value>\d+)、?"#).unwrap();
+        static ref SEE_ALSO: Regex = Regex::new(r#"(?:^|[、。])\s*(?:→|⇒|￫|->)\s*(?P<value>[^【◆■、。（]+)"#).unwrap();
     }
     let cap = RE.captures(text).ok_or(anyhow!("Invalid field format"))?;
     let key = cap["item"].to_string();
+    let mut complements = parse_complements(&cap["complements"])?;
+    for c in &mut complements {
+        c.body = collapse_spaces(&c.body);
+    }
+    let mut explanation = Explanation {
+        body: collapse_spaces(&cap["exp"]),
+        complements,
+        pronunciation: None,
+        senses: Vec::new(),
+    };
+    explanation.pronunciation = take_marker(&mut explanation, &PRONUNCIATION);
+    let level = take_marker(&mut explanation, &LEVEL).and_then(|l| l.parse().ok());
+    let rank = take_marker(&mut explanation, &RANK).and_then(|r| r.parse().ok());
+    let mut see_also = Vec::new();
+    while let Some(targets) = take_marker(&mut explanation, &SEE_ALSO) {
+        see_also.extend(split_targets(&targets));
+    }
+    explanation.senses = split_senses(&explanation.body);
     Ok((
         key,
         Field {
             ident: cap.name("ident").map(|m| m.as_str().to_string()),
-            explanation: {
-                Explanation {
-                    body: cap["exp"].to_string(),
-                    complements: parse_complements(&cap["complements"])?,
-                }
-            },
+            pos: cap
+                .name("ident")
+                .map(|m| PartOfSpeech::from_ident(m.as_str())),
+            level,
+            rank,
+            explanation,
             examples: parse_examples(&cap["examples"])?,
+            see_also,
+            raw: None,
         },
     ))
 }
 
-pub fn parse(text: &str) -> Result<Dict> {
-    let mut tmp = text
-        .lines()
-        .enumerate()
-        .map(|(line_no, line)| {
-            let (k, f) = parse_field(line).map_err(|e| anyhow!("line {}: {}", line_no, e))?;
-            Ok((k, f, line_no))
-        })
-        .collect::<Result<Vec<_>>>()?;
-    tmp.sort();
+/// The headwords in a cross-reference like `→run; walk`.
+fn split_targets(targets: &str) -> Vec<String> {
+    targets
+        .split(['、', '，', ',', ';', '；'])
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Splits a body packing several numbered senses (①②③ or `1. 2. 3.`) into
+/// one string per sense, each keeping its number. A body without sense
+/// numbers is returned as a single element.
+pub fn split_senses(body: &str) -> Vec<String> {
+    lazy_static! {
+        static ref NUMBER: Regex = Regex::new(r#"[①-⑳]|(?:^|\s)(\d+)\.\s"#).unwrap();
+    }
+    let mut starts = NUMBER
+        .captures_iter(body)
+        .map(|cap| cap.get(1).unwrap_or_else(|| cap.get(0).unwrap()).start())
+        .collect::<Vec<_>>();
+    if starts.is_empty() {
+        return vec![body.to_string()];
+    }
+    if starts[0] != 0 {
+        starts.insert(0, 0);
+    }
+    starts.push(body.len());
+    starts
+        .windows(2)
+        .map(|w| body[w[0]..w[1]].trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Splits a Japanese explanation into lookup tokens for the reverse index.
+/// Bracketed annotations such as 《コ》 or 〔入力文字の〕 are dropped and the
+/// rest is split on list separators like 、 and ；.
+pub fn tokenize_explanation(body: &str) -> Vec<String> {
+    lazy_static! {
+        static ref ANNOTATION: Regex =
+            Regex::new(r#"《[^》]*》|〔[^〕]*〕|〈[^〉]*〉|【[^】]*】|（[^）]*）|\([^)]*\)|［[^］]*］|\[[^\]]*\]"#)
+                .unwrap();
+        static ref SEPARATOR: Regex = Regex::new(r#"[、，,；;／/]"#).unwrap();
+    }
+    let stripped = ANNOTATION.replace_all(body, "");
+    let mut tokens: Vec<String> = SEPARATOR
+        .split(&stripped)
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect();
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+fn build_reverse(keys: &Map<Vec<u8>>, fields: &[Vec<Field>]) -> Reverse {
+    let mut reverse: HashMap<String, Vec<ReverseEntry>> = HashMap::new();
+    let mut stream = keys.stream();
+    while let Some((k, idx)) = stream.next() {
+        let headword = String::from_utf8_lossy(k).into_owned();
+        for (pos, f) in fields[idx as usize].iter().enumerate() {
+            for token in tokenize_explanation(&f.explanation.body) {
+                reverse.entry(token).or_default().push(ReverseEntry {
+                    headword: headword.clone(),
+                    idx: idx as u32,
+                    field: pos as u32,
+                });
+            }
+        }
+    }
+    Reverse::from(reverse)
+}
+
+/// `text` with spaces and hyphens removed, so "look up", "look-up" and
+/// "lookup" all share the form "lookup".
+pub fn canonical_phrase(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, '-' | '‐' | '‑'))
+        .collect()
+}
+
+/// Possible base forms of an inflected English `word`, most likely first:
+/// "running" gives "run", "runn" and "runne", "hoping" gives "hope" before
+/// "hop", "studies" gives "study". Only regular -s, -es, -ies, -ed, -ied and
+/// -ing endings are stripped, so the candidates are guesses to look up, not
+/// words; irregular forms such as "ran" yield nothing. The word itself is
+/// never included.
+pub fn stem_candidates(word: &str) -> Vec<String> {
+    let lower = word.to_lowercase();
+    if lower != word {
+        return stem_candidates(&lower);
+    }
+    let is_vowel = |c: char| "aeiou".contains(c);
+    let mut candidates: Vec<String> = Vec::new();
+    let mut push = |stem: &str| {
+        // Keep at least two characters, so "is" does not become "i".
+        if stem.chars().count() >= 2 && stem != word && !candidates.iter().any(|c| c == stem) {
+            candidates.push(stem.to_string());
+        }
+    };
+    for (suffix, replacement) in [("ies", "y"), ("ied", "y")] {
+        if let Some(stem) = word.strip_suffix(suffix) {
+            push(&format!("{}{}", stem, replacement));
+        }
+    }
+    for suffix in ["ing", "ed"] {
+        if let Some(stem) = word.strip_suffix(suffix) {
+            let mut chars = stem.chars().rev();
+            let (last, before) = (chars.next(), chars.next());
+            match (last, before) {
+                // running -> run, stopped -> stop; but falling -> fall and
+                // passed -> pass, whose base forms end in the double letter.
+                (Some(a), Some(b)) if a == b && !is_vowel(a) => {
+                    let undoubled = &stem[..stem.len() - a.len_utf8()];
+                    if "lsfz".contains(a) {
+                        push(stem);
+                        push(undoubled);
+                    } else {
+                        push(undoubled);
+                        push(stem);
+                    }
+                }
+                // hoping -> hope, rated -> rate, used -> use: a single vowel
+                // before the last consonant usually means a dropped "e".
+                (Some(a), Some(b))
+                    if !is_vowel(a)
+                        && !"wxy".contains(a)
+                        && is_vowel(b)
+                        && chars.next().is_none_or(|c| !is_vowel(c)) =>
+                {
+                    push(&format!("{}e", stem));
+                    push(stem);
+                }
+                _ => push(stem),
+            }
+            // making -> make, liked -> like
+            push(&format!("{}e", stem));
+        }
+    }
+    if let Some(stem) = word.strip_suffix("es") {
+        let sibilant = ["ss", "x", "z", "ch", "sh"]
+            .iter()
+            .any(|s| stem.ends_with(s));
+        if sibilant {
+            // boxes -> box, wishes -> wish; aches -> ache is tried after.
+            push(stem);
+        } else if stem.ends_with('s') {
+            // uses -> use before buses -> bus
+            push(&word[..word.len() - 1]);
+            push(stem);
+        }
+    }
+    if let Some(stem) = word.strip_suffix('s').filter(|stem| !stem.ends_with('s')) {
+        push(stem);
+    }
+    candidates
+}
+
+fn build_phrases<D: AsRef<[u8]>>(keys: &Map<D>) -> Phrases {
+    let mut phrases: HashMap<String, Vec<String>> = HashMap::new();
+    let mut stream = keys.stream();
+    while let Some((k, _)) = stream.next() {
+        let headword = String::from_utf8_lossy(k);
+        let canonical = canonical_phrase(&headword);
+        // Headwords without spaces or hyphens are found through `keys`.
+        if canonical != headword {
+            phrases
+                .entry(canonical)
+                .or_default()
+                .push(headword.into_owned());
+        }
+    }
+    Phrases::from(phrases)
+}
+
+/// Error for a source line that could not be parsed.
+/// `parse` and `parse_reader` return it inside `anyhow::Error`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based line number.
+    pub line: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}: {}", self.line, self.reason, self.text)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Settings for `parse_with` and `parse_reader_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    /// Keep every source line in `Field::raw`, for debugging the parser.
+    /// Roughly doubles the size of the dictionary.
+    pub keep_raw: bool,
+}
+
+/// What `Dict::new_from_entries_with` does with a headword given twice.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DuplicateKeys {
+    /// Fail with the repeated headword.
+    #[default]
+    Error,
+    /// Append the later fields not already present, like `Dict::merge`.
+    Merge,
+}
+
+fn parse_line(
+    line_no: usize,
+    line: &str,
+    opts: ParseOptions,
+) -> std::result::Result<(String, Field, usize), ParseError> {
+    let (k, mut f) = parse_field(line).map_err(|e| ParseError {
+        line: line_no + 1,
+        text: line.to_string(),
+        reason: e.to_string(),
+    })?;
+    if opts.keep_raw {
+        f.raw = Some(line.to_string());
+    }
+    Ok((k, f, line_no))
+}
+
+pub fn parse(text: &str) -> Result<Dict> {
+    parse_with(text, ParseOptions::default())
+}
+
+pub fn parse_with(text: &str, opts: ParseOptions) -> Result<Dict> {
+    let lines = text.lines().collect::<Vec<_>>();
+    build_dict(parse_lines(0, &lines, opts, None)?)
+}
+
+/// Like `parse_with`, but skips the lines that cannot be parsed instead of
+/// failing, and returns them in source order.
+pub fn parse_with_skipped(text: &str, opts: ParseOptions) -> Result<(Dict, Vec<ParseError>)> {
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut skipped = Vec::new();
+    let dict = build_dict(parse_lines(0, &lines, opts, Some(&mut skipped))?)?;
+    Ok((dict, skipped))
+}
+
+/// Lines handed to rayon at a time by `parse_reader`.
+const PARSE_CHUNK_LINES: usize = 1 << 16;
+
+/// Like `parse`, but reads the source in chunks so the whole text never
+/// has to be held in memory at once.
+pub fn parse_reader<R: BufRead>(reader: R) -> Result<Dict> {
+    parse_reader_with(reader, ParseOptions::default())
+}
+
+pub fn parse_reader_with<R: BufRead>(reader: R, opts: ParseOptions) -> Result<Dict> {
+    parse_chunks(reader, opts, None)
+}
+
+/// Like `parse_reader_with`, but skips the lines that cannot be parsed
+/// instead of failing, and returns them in source order. Read errors still
+/// fail.
+pub fn parse_reader_with_skipped<R: BufRead>(
+    reader: R,
+    opts: ParseOptions,
+) -> Result<(Dict, Vec<ParseError>)> {
+    let mut skipped = Vec::new();
+    let dict = parse_chunks(reader, opts, Some(&mut skipped))?;
+    Ok((dict, skipped))
+}
+
+/// What `validate_reader` found in a source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validation {
+    /// Distinct headwords.
+    pub headwords: usize,
+    pub fields: usize,
+    /// Lines that could not be parsed, in source order.
+    pub skipped: Vec<ParseError>,
+}
+
+impl Validation {
+    /// Whether every line parsed.
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty()
+    }
+}
+
+/// Parses `reader` chunk by chunk like `parse_reader`, but only counts
+/// what it finds: no dictionary is built, and only the headwords are kept.
+/// Read errors still fail.
+pub fn validate_reader<R: BufRead>(reader: R) -> Result<Validation> {
+    let mut skipped = Vec::new();
+    let mut fields = 0;
+    let mut headwords = std::collections::HashSet::new();
+    for_each_chunk(
+        reader,
+        ParseOptions::default(),
+        Some(&mut skipped),
+        |chunk| {
+            fields += chunk.len();
+            headwords.extend(chunk.into_iter().map(|(k, _, _)| k));
+        },
+    )?;
+    Ok(Validation {
+        headwords: headwords.len(),
+        fields,
+        skipped,
+    })
+}
+
+fn parse_chunks<R: BufRead>(
+    reader: R,
+    opts: ParseOptions,
+    skipped: Option<&mut Vec<ParseError>>,
+) -> Result<Dict> {
+    let mut tmp = Vec::new();
+    for_each_chunk(reader, opts, skipped, |mut fields| tmp.append(&mut fields))?;
+    build_dict(tmp)
+}
+
+/// Reads `reader` in chunks of `PARSE_CHUNK_LINES` and hands the parsed
+/// fields of each to `sink`.
+fn for_each_chunk<R: BufRead>(
+    reader: R,
+    opts: ParseOptions,
+    mut skipped: Option<&mut Vec<ParseError>>,
+    mut sink: impl FnMut(Vec<(String, Field, usize)>),
+) -> Result<()> {
+    let mut chunk = Vec::with_capacity(PARSE_CHUNK_LINES);
+    let mut chunk_start = 0;
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| ParseError {
+            line: line_no + 1,
+            text: String::new(),
+            reason: e.to_string(),
+        })?;
+        chunk.push(line);
+        if chunk.len() == PARSE_CHUNK_LINES {
+            sink(parse_lines(
+                chunk_start,
+                &chunk,
+                opts,
+                skipped.as_deref_mut(),
+            )?);
+            chunk_start = line_no + 1;
+            chunk.clear();
+        }
+    }
+    sink(parse_lines(chunk_start, &chunk, opts, skipped)?);
+    Ok(())
+}
+
+/// Parses `lines`, in parallel with the `parallel` feature; `first_line` is the 0-based line number of
+/// `lines[0]`. Reports the earliest failing line, as a serial parse would,
+/// or with `skipped` appends every failing line to it and goes on.
+fn parse_lines<S: AsRef<str> + Sync>(
+    first_line: usize,
+    lines: &[S],
+    opts: ParseOptions,
+    mut skipped: Option<&mut Vec<ParseError>>,
+) -> Result<Vec<(String, Field, usize)>> {
+    #[cfg(feature = "parallel")]
+    let lines = lines.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let lines = lines.iter();
+    let parsed = lines
+        .enumerate()
+        .map(|(i, line)| parse_line(first_line + i, line.as_ref(), opts))
+        .collect::<Vec<_>>();
+    let mut tmp = Vec::with_capacity(parsed.len());
+    for field in parsed {
+        match (field, skipped.as_deref_mut()) {
+            (Ok(field), _) => tmp.push(field),
+            (Err(e), Some(skipped)) => skipped.push(e),
+            (Err(e), None) => return Err(e.into()),
+        }
+    }
+    Ok(tmp)
+}
+
+fn build_dict(mut tmp: Vec<(String, Field, usize)>) -> Result<Dict> {
+    // Sorted by headword, then by line: the fields of a headword keep the
+    // order of the source, where the most common sense comes first. Line
+    // numbers are distinct, so an unstable sort is exact.
+    let by_line =
+        |a: &(String, Field, usize), b: &(String, Field, usize)| (&a.0, a.2).cmp(&(&b.0, b.2));
+    #[cfg(feature = "parallel")]
+    tmp.par_sort_unstable_by(by_line);
+    #[cfg(not(feature = "parallel"))]
+    tmp.sort_unstable_by(by_line);
+
+    let mut map = MapBuilder::memory();
+    let mut prev_key: Option<String> = None;
+    let mut fields = Vec::new();
+    for (k, f, line_no) in tmp.into_iter() {
+        let new_key = prev_key.as_ref().map(|p| p != &k).unwrap_or(true);
+        if new_key {
+            map.insert(&k, fields.len() as u64)
+                .map_err(|e| anyhow!("line {}: {}", line_no + 1, e))?;
+            fields.push(Vec::new());
+            prev_key = Some(k);
+        }
+        // Some sources repeat a line verbatim for the same headword; the
+        // first occurrence keeps its place.
+        let current = fields.last_mut().unwrap();
+        if !current.contains(&f) {
+            current.push(f);
+        }
+    }
+    assemble(map.into_map(), fields, None)
+}
+
+/// Builds the secondary indexes for `keys` and `fields`.
+fn assemble(
+    keys: Map<Vec<u8>>,
+    fields: Vec<Vec<Field>>,
+    source: Option<SourceStamp>,
+) -> Result<Dict> {
+    let reverse = build_reverse(&keys, &fields);
+    let keys = Map::new(Storage::Owned(keys.into_fst().into_inner()))?;
+    let folded = FoldedKeys::build(&keys)?;
+    let phrases = build_phrases(&keys);
+    Ok(Dict {
+        keys,
+        fields: Fields::from(fields),
+        reverse,
+        folded,
+        phrases,
+        source,
+    })
+}
+
+/// Looks up every headword within Levenshtein `distance` of `word`.
+/// Results are returned in key (lexicographic) order.
+pub fn search<'a>(dict: &'a Dict, word: &str, distance: u32) -> Result<Vec<(String, &'a [Field])>> {
+    search_with(dict, &*levenshtein(word, distance)?)
+}
+
+/// Automata remembered by `levenshtein`.
+const LEVENSHTEIN_CACHE_LEN: usize = 16;
+
+/// Builds the automaton for `word` at `distance`, or reuses one of the
+/// last `LEVENSHTEIN_CACHE_LEN` built, most recently used first.
+///
+/// Construction is most of the cost of a fuzzy search: on the 20k-word
+/// bench fixture, building for a six-letter word takes about 110µs at
+/// distance 1 and 800µs at distance 2, as long as the search itself. A REPL
+/// or GUI session repeating a query (or a `--limit` page of it) skips that.
+fn levenshtein(word: &str, distance: u32) -> Result<Arc<fst::automaton::Levenshtein>> {
+    type Cache = VecDeque<(String, u32, Arc<fst::automaton::Levenshtein>)>;
+    lazy_static! {
+        static ref CACHE: Mutex<Cache> = Mutex::new(VecDeque::new());
+    }
+    let lock = || CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    {
+        let mut cache = lock();
+        if let Some(i) = cache
+            .iter()
+            .position(|(w, d, _)| w == word && *d == distance)
+        {
+            let hit = cache.remove(i).unwrap();
+            let automaton = hit.2.clone();
+            cache.push_front(hit);
+            return Ok(automaton);
+        }
+    }
+    // Built without holding the lock, so other threads can still hit.
+    let automaton = Arc::new(fst::automaton::Levenshtein::new(word, distance)?);
+    let mut cache = lock();
+    cache.push_front((word.to_string(), distance, automaton.clone()));
+    cache.truncate(LEVENSHTEIN_CACHE_LEN);
+    Ok(automaton)
+}
+
+/// Looks up every headword starting with `prefix`, in key order.
+pub fn prefix_search<'a>(dict: &'a Dict, prefix: &str) -> Result<Vec<(String, &'a [Field])>> {
+    search_with(dict, fst::automaton::Str::new(prefix).starts_with())
+}
+
+/// One window of a `search`, returned by `search_page`.
+#[derive(Debug, Clone)]
+pub struct SearchPage<'a> {
+    /// Matches over the whole dictionary, not just this page.
+    pub total: usize,
+    /// At most `limit` matches starting at `offset`, in key order.
+    pub results: Vec<(String, &'a [Field])>,
+}
+
+/// Like `search`, but returns only the matches in `offset..offset + limit`
+/// along with the total count. Counting drains the whole FST stream, so
+/// this costs as much as a full `search` on the key index; only the fields
+/// of the returned page are decoded, though.
+pub fn search_page<'a>(
+    dict: &'a Dict,
+    word: &str,
+    distance: u32,
+    offset: usize,
+    limit: usize,
+) -> Result<SearchPage<'a>> {
+    let matcher = levenshtein(word, distance)?;
+    let mut stream = dict.keys.search(&*matcher).into_stream();
+    let mut page = SearchPage {
+        total: 0,
+        results: Vec::new(),
+    };
+    while let Some((k, idx)) = stream.next() {
+        let in_page = page.total >= offset && page.total - offset < limit;
+        page.total += 1;
+        if !in_page {
+            continue;
+        }
+        let key = decode_key(k).into_owned();
+        match dict.fields_for(idx) {
+            Some(fields) => page.results.push((key, fields.as_slice())),
+            None => warn_missing_fields(&key, idx),
+        }
+    }
+    Ok(page)
+}
+
+/// Up to `max` headwords starting with `prefix`, in key order. Only the key
+/// FST is walked, so no fields are decoded; meant for completion.
+pub fn prefix_keys(dict: &Dict, prefix: &str, max: usize) -> Vec<String> {
+    let mut stream = dict
+        .keys
+        .search(fst::automaton::Str::new(prefix).starts_with())
+        .into_stream();
+    let mut ret = Vec::new();
+    while ret.len() < max {
+        match stream.next() {
+            Some((k, _)) => ret.push(decode_key(k).into_owned()),
+            None => break,
+        }
+    }
+    ret
+}
+
+/// Like `search`, but `word` is lowercased and matched against `Dict::folded`,
+/// so "Apple" also finds "apple". Each hit reports the original headword.
+pub fn search_ignore_case<'a>(
+    dict: &'a Dict,
+    word: &str,
+    distance: u32,
+) -> Result<Vec<(String, &'a [Field])>> {
+    search_folded_with(dict, &*levenshtein(&word.to_lowercase(), distance)?)
+}
+
+/// Case-insensitive `prefix_search`.
+pub fn prefix_search_ignore_case<'a>(
+    dict: &'a Dict,
+    prefix: &str,
+) -> Result<Vec<(String, &'a [Field])>> {
+    let prefix = prefix.to_lowercase();
+    search_folded_with(dict, fst::automaton::Str::new(&prefix).starts_with())
+}
+
+/// Looks up every headword matching a glob `pattern`, where `*` matches any
+/// run of characters and `?` matches exactly one. The whole key must match.
+pub fn glob_search<'a>(dict: &'a Dict, pattern: &str) -> Result<Vec<(String, &'a [Field])>> {
+    search_with(dict, &glob_dfa(pattern)?)
+}
+
+/// Case-insensitive `glob_search`.
+pub fn glob_search_ignore_case<'a>(
+    dict: &'a Dict,
+    pattern: &str,
+) -> Result<Vec<(String, &'a [Field])>> {
+    search_folded_with(dict, &glob_dfa(&pattern.to_lowercase())?)
+}
+
+/// Looks up every headword containing `needle` as a literal substring.
+/// Scanning stops once `limit` headwords have been found, since a short
+/// needle can match a large part of the dictionary.
+pub fn contains_search<'a>(
+    dict: &'a Dict,
+    needle: &str,
+    limit: Option<usize>,
+) -> Result<Vec<(String, &'a [Field])>> {
+    search_with_limit(dict, &contains_dfa(needle)?, limit)
+}
+
+/// Case-insensitive `contains_search`.
+pub fn contains_search_ignore_case<'a>(
+    dict: &'a Dict,
+    needle: &str,
+    limit: Option<usize>,
+) -> Result<Vec<(String, &'a [Field])>> {
+    search_folded_with_limit(dict, &contains_dfa(&needle.to_lowercase())?, limit)
+}
+
+/// Looks up every headword matching the regular expression `pattern`,
+/// stopping after `limit` headwords. The pattern is implicitly anchored at
+/// both ends, since the automaton must accept the whole key: `run` finds
+/// only "run", and `run.*` is needed for "runner". `^` and `$` are
+/// therefore unneeded, and look-around and word boundaries are not
+/// supported.
+pub fn regex_search<'a>(
+    dict: &'a Dict,
+    pattern: &str,
+    limit: Option<usize>,
+) -> Result<Vec<(String, &'a [Field])>> {
+    search_with_limit(dict, &regex_dfa(pattern)?, limit)
+}
+
+/// Case-insensitive `regex_search`.
+pub fn regex_search_ignore_case<'a>(
+    dict: &'a Dict,
+    pattern: &str,
+    limit: Option<usize>,
+) -> Result<Vec<(String, &'a [Field])>> {
+    // Lowercasing the pattern would turn `\D` into `\d`; the keys of the
+    // folded index are lowercase already.
+    search_folded_with_limit(dict, &regex_dfa(&format!("(?i:{})", pattern))?, limit)
+}
+
+/// A headword pattern for `for_each_match`, as taken by `prefix_search`,
+/// `glob_search`, `contains_search` and `regex_search`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPattern<'p> {
+    Prefix(&'p str),
+    Glob(&'p str),
+    Contains(&'p str),
+    Regex(&'p str),
+}
+
+/// Calls `visit` with each headword matching `pattern`, in key order, as the
+/// FST stream yields it, instead of collecting the matches first like
+/// `prefix_search` and friends. Scanning stops when `visit` returns false.
+/// With `ignore_case`, matches as the `_ignore_case` searches do.
+pub fn for_each_match<'a>(
+    dict: &'a Dict,
+    pattern: KeyPattern,
+    ignore_case: bool,
+    mut visit: impl FnMut(String, &'a [Field]) -> bool,
+) -> Result<()> {
+    fn scan<'a, A: Automaton>(
+        dict: &'a Dict,
+        matcher: A,
+        ignore_case: bool,
+        visit: &mut dyn FnMut(String, &'a [Field]) -> bool,
+    ) -> Result<()> {
+        if ignore_case {
+            visit_folded_with(dict, matcher, visit)
+        } else {
+            visit_with(dict, matcher, visit)
+        }
+    }
+    let fold = |text: &str| {
+        if ignore_case {
+            text.to_lowercase()
+        } else {
+            text.to_string()
+        }
+    };
+    match pattern {
+        KeyPattern::Prefix(prefix) => {
+            let prefix = fold(prefix);
+            let matcher = fst::automaton::Str::new(&prefix).starts_with();
+            scan(dict, matcher, ignore_case, &mut visit)
+        }
+        KeyPattern::Glob(glob) => scan(dict, &glob_dfa(&fold(glob))?, ignore_case, &mut visit),
+        KeyPattern::Contains(needle) => {
+            scan(dict, &contains_dfa(&fold(needle))?, ignore_case, &mut visit)
+        }
+        // See `regex_search_ignore_case` for why the pattern is not lowercased.
+        KeyPattern::Regex(pattern) if ignore_case => scan(
+            dict,
+            &regex_dfa(&format!("(?i:{})", pattern))?,
+            true,
+            &mut visit,
+        ),
+        KeyPattern::Regex(pattern) => scan(dict, &regex_dfa(pattern)?, false, &mut visit),
+    }
+}
+
+/// Headwords checked at a time by `body_search`.
+const BODY_SEARCH_BATCH: usize = 4096;
+
+/// Looks up every headword with a field whose explanation or complements
+/// contain `needle`, stopping after `limit` headwords. Unlike
+/// `reverse_search` this is a linear scan over all fields, run in parallel
+/// with the `parallel` feature, and works for any text, e.g. an English
+/// word in a definition.
+pub fn body_search<'a>(
+    dict: &'a Dict,
+    needle: &str,
+    limit: Option<usize>,
+) -> Vec<(String, &'a [Field])> {
+    body_search_with(dict, limit, |text| text.contains(needle))
+}
+
+/// Case-insensitive `body_search`.
+pub fn body_search_ignore_case<'a>(
+    dict: &'a Dict,
+    needle: &str,
+    limit: Option<usize>,
+) -> Vec<(String, &'a [Field])> {
+    let needle = needle.to_lowercase();
+    body_search_with(dict, limit, |text| text.to_lowercase().contains(&needle))
+}
+
+fn body_search_with(
+    dict: &Dict,
+    limit: Option<usize>,
+    matches: impl Fn(&str) -> bool + Sync,
+) -> Vec<(String, &[Field])> {
+    let limit = limit.unwrap_or(usize::MAX);
+    let has_match = |fields: &[Field]| {
+        fields.iter().any(|f| {
+            matches(&f.explanation.body)
+                || f.explanation.complements.iter().any(|c| matches(&c.body))
+        })
+    };
+    let mut stream = dict.keys.stream();
+    let mut batch = Vec::with_capacity(BODY_SEARCH_BATCH);
+    let mut ret = Vec::new();
+    loop {
+        // The FST is walked in order and only the fields are checked in
+        // parallel, so matches keep the headword order and the scan can
+        // stop at `limit`.
+        batch.clear();
+        while batch.len() < BODY_SEARCH_BATCH {
+            match stream.next() {
+                Some((k, idx)) => batch.push((decode_key(k).into_owned(), idx)),
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            return ret;
+        }
+        #[cfg(feature = "parallel")]
+        let candidates = batch.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let candidates = batch.iter();
+        let found = candidates
+            .filter_map(|(key, idx)| match dict.fields_for(*idx) {
+                Some(fields) => has_match(fields).then(|| (key.clone(), fields.as_slice())),
+                None => {
+                    warn_missing_fields(key, *idx);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        ret.extend(found.into_iter().take(limit - ret.len()));
+        if ret.len() == limit {
+            return ret;
+        }
+    }
+}
+
+fn regex_dfa(pattern: &str) -> Result<regex_automata::DenseDFA<Vec<usize>, usize>> {
+    regex_automata::dense::Builder::new()
+        .anchored(true)
+        .longest_match(true)
+        .build(pattern)
+        .map_err(|e| anyhow!("invalid regex {:?}: {}", pattern, e))
+}
+
+fn contains_dfa(needle: &str) -> Result<regex_automata::DenseDFA<Vec<usize>, usize>> {
+    let dfa = regex_automata::dense::Builder::new()
+        .anchored(true)
+        .longest_match(true)
+        .build(&format!(".*{}.*", regex::escape(needle)))?;
+    Ok(dfa)
+}
+
+fn glob_dfa(pattern: &str) -> Result<regex_automata::DenseDFA<Vec<usize>, usize>> {
+    let mut re = String::new();
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    let dfa = regex_automata::dense::Builder::new()
+        .anchored(true)
+        .longest_match(true)
+        .build(&re)?;
+    Ok(dfa)
+}
+
+fn search_with<A: Automaton>(dict: &Dict, matcher: A) -> Result<Vec<(String, &[Field])>> {
+    search_with_limit(dict, matcher, None)
+}
+
+fn search_with_limit<A: Automaton>(
+    dict: &Dict,
+    matcher: A,
+    limit: Option<usize>,
+) -> Result<Vec<(String, &[Field])>> {
+    collect_matches(limit, |visit| visit_with(dict, matcher, visit))
+}
+
+/// Runs a `visit_with`-style scan, collecting at most `limit` matches.
+fn collect_matches<'a>(
+    limit: Option<usize>,
+    scan: impl FnOnce(&mut dyn FnMut(String, &'a [Field]) -> bool) -> Result<()>,
+) -> Result<Vec<(String, &'a [Field])>> {
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut ret = Vec::new();
+    if limit > 0 {
+        scan(&mut |key, fields| {
+            ret.push((key, fields));
+            ret.len() < limit
+        })?;
+    }
+    Ok(ret)
+}
+
+/// Calls `visit` with each headword `matcher` accepts, in key order, until
+/// it returns false.
+fn visit_with<'a, A: Automaton>(
+    dict: &'a Dict,
+    matcher: A,
+    visit: &mut dyn FnMut(String, &'a [Field]) -> bool,
+) -> Result<()> {
+    let mut stream = dict.keys.search(matcher).into_stream();
+    while let Some((k, idx)) = stream.next() {
+        let key = decode_key(k).into_owned();
+        match dict.fields_for(idx) {
+            Some(fields) => {
+                if !visit(key, fields.as_slice()) {
+                    break;
+                }
+            }
+            None => warn_missing_fields(&key, idx),
+        }
+    }
+    Ok(())
+}
+
+fn warn_missing_fields(headword: &str, idx: u64) {
+    warn!(
+        "no fields stored for {} (index {}); skipping",
+        headword, idx
+    );
+}
+
+/// Decodes a stored key, replacing invalid UTF-8 (left by a mis-parsed
+/// source) instead of failing the whole search over one key.
+fn decode_key(k: &[u8]) -> Cow<'_, str> {
+    let key = String::from_utf8_lossy(k);
+    if let Cow::Owned(_) = key {
+        warn!("key is not valid UTF-8: {:?}", k);
+    }
+    key
+}
+
+fn search_folded_with<A: Automaton>(dict: &Dict, matcher: A) -> Result<Vec<(String, &[Field])>> {
+    search_folded_with_limit(dict, matcher, None)
+}
+
+fn search_folded_with_limit<A: Automaton>(
+    dict: &Dict,
+    matcher: A,
+    limit: Option<usize>,
+) -> Result<Vec<(String, &[Field])>> {
+    collect_matches(limit, |visit| visit_folded_with(dict, matcher, visit))
+}
+
+/// `visit_with` over `Dict::folded`, visiting every original headword of a
+/// matching folded key.
+fn visit_folded_with<'a, A: Automaton>(
+    dict: &'a Dict,
+    matcher: A,
+    visit: &mut dyn FnMut(String, &'a [Field]) -> bool,
+) -> Result<()> {
+    let mut stream = dict.folded.keys.search(matcher).into_stream();
+    while let Some((k, group)) = stream.next() {
+        let folded = decode_key(k).into_owned();
+        let variants = dict
+            .folded
+            .groups
+            .get(group as usize)
+            .ok_or_else(|| anyhow!("folded key {} has no group {}", folded, group))?;
+        for variant in variants {
+            let key = variant.as_deref().unwrap_or(&folded);
+            let idx = match dict.keys.get(key) {
+                Some(idx) => idx,
+                // `FoldedKeys::build` decoded an invalid original lossily,
+                // so it cannot be looked up again.
+                None if key.contains(char::REPLACEMENT_CHARACTER) => {
+                    warn!("skipping headword that is not valid UTF-8: {}", key);
+                    continue;
+                }
+                None => return Err(anyhow!("folded key {} has no headword {}", folded, key)),
+            };
+            match dict.fields_for(idx) {
+                Some(fields) => {
+                    if !visit(key.to_string(), fields.as_slice()) {
+                        return Ok(());
+                    }
+                }
+                None => warn_missing_fields(key, idx),
+            }
+        }
+    }
+    Ok(())
+}
 
-    let mut map = MapBuilder::memory();
-    let mut prev_key: Option<String> = None;
-    let mut fields = Vec::new();
-    for (k, f, line_no) in tmp.into_iter() {
-        let new_key = prev_key.as_ref().map(|p| p != &k).unwrap_or(true);
-        if new_key {
-            map.insert(&k, fields.len() as u64)
-                .map_err(|e| anyhow!("[line {}]: {}", line_no, e))?;
-            fields.push(Vec::new());
-            prev_key = Some(k);
+/// Levenshtein distance between `a` and `b` counted in `char`s, so a
+/// Japanese character costs one edit rather than the three UTF-8 bytes the
+/// FST automata see. Use it to rank candidates the byte-based search found.
+pub fn levenshtein_chars(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = subst.min(prev[j + 1] + 1).min(cur[j] + 1);
         }
-        fields.last_mut().unwrap().push(f);
+        prev = cur;
     }
-    Ok(Dict {
-        keys: map.into_map(),
-        fields,
-    })
+    prev[b.len()]
+}
+
+/// Looks up every headword equal to `phrase` once spaces and hyphens are
+/// ignored (see `canonical_phrase`), in key order.
+pub fn phrase_search<'a>(dict: &'a Dict, phrase: &str) -> Vec<(String, &'a [Field])> {
+    let canonical = canonical_phrase(phrase);
+    let mut headwords = dict.phrases.get(&canonical).cloned().unwrap_or_default();
+    if dict.contains(&canonical) {
+        headwords.push(canonical);
+    }
+    headwords.sort();
+    headwords
+        .into_iter()
+        .filter_map(|k| {
+            let fields = dict.get(&k)?;
+            Some((k, fields))
+        })
+        .collect()
+}
+
+/// A stable reference to the `position`-th field (0-based) of `headword`,
+/// written `headword#N` with `N` counted from 1; `Dict::get_by_id` resolves it.
+///
+/// `N` is the field's position in `Dict::get(headword)`, so an ID stays
+/// valid across rebuilds from the same source. `Dict::merge` only appends
+/// fields, so merging keeps existing IDs too. Editing the source lines of
+/// a headword may renumber its fields. Headwords containing `#` still
+/// work, as only the last `#` separates the position.
+pub fn field_id(headword: &str, position: usize) -> String {
+    format!("{}#{}", headword, position + 1)
+}
+
+/// Finds the headwords whose explanation contains `token` (see `tokenize_explanation`).
+pub fn reverse_search<'a>(dict: &'a Dict, token: &str) -> Vec<(&'a str, &'a Field)> {
+    dict.reverse
+        .get(token)
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|e| {
+                    let field = dict
+                        .fields_for(u64::from(e.idx))
+                        .and_then(|fields| fields.get(e.field as usize));
+                    if field.is_none() {
+                        warn_missing_fields(&e.headword, u64::from(e.idx));
+                    }
+                    Some((e.headword.as_str(), field?))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
 #[cfg(test)]
@@ -176,7 +1857,6 @@ mod tests {
     use super::*;
 
     fn kv_vec(dict: &Dict) -> Vec<(String, &Vec<Field>)> {
-        use fst::Streamer;
         let mut ret = Vec::new();
         let mut stream = dict.keys.stream();
         while let Some((k, idx)) = stream.next() {
@@ -192,25 +1872,35 @@ mod tests {
         exp_coms: Vec<S>,
         examples: Vec<(S, Vec<S>)>,
     ) -> Field {
+        let ident = ident.map(|s| s.into());
+        let body: String = exp.into();
         Field {
-            ident: ident.map(|s| s.into()),
+            pos: ident.as_deref().map(PartOfSpeech::from_ident),
+            ident,
+            level: None,
+            rank: None,
             explanation: Explanation {
-                body: exp.into(),
+                senses: split_senses(&body),
+                body,
                 complements: exp_coms
                     .into_iter()
                     .map(|s| Complement { body: s.into() })
                     .collect(),
+                pronunciation: None,
             },
             examples: examples
                 .into_iter()
-                .map(|(s, c)| Example {
-                    sentence: s.into(),
-                    complements: c
-                        .into_iter()
-                        .map(|c| Complement { body: c.into() })
-                        .collect(),
+                .map(|(s, c)| {
+                    Example::new(
+                        s.into(),
+                        c.into_iter()
+                            .map(|c| Complement { body: c.into() })
+                            .collect(),
+                    )
                 })
                 .collect(),
+            see_also: Vec::new(),
+            raw: None,
         }
     }
 
@@ -308,6 +1998,266 @@ mod tests {
         )
     }
 
+    #[test]
+    fn reader() {
+        let s = "■xxx : aaa◆bbb◆ccc■ddd◆eee■fff\n■run {動} : 走る\n";
+        let dict = parse_reader(s.as_bytes()).unwrap();
+        assert_eq!(kv_vec(&dict), kv_vec(&parse(s).unwrap()));
+
+        let err = parse_reader(&b"\xff\xfe"[..]).unwrap_err();
+        assert_eq!(err.downcast_ref::<ParseError>().unwrap().line, 1);
+    }
+
+    #[test]
+    fn parse_error_line() {
+        let s = "■xxx : aaa\n■yyy : bbb\nzzz : ccc";
+        let err = parse(s).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError {
+                line: 3,
+                text: "zzz : ccc".to_string(),
+                reason: "Invalid field format".to_string(),
+            })
+        );
+        assert_eq!(err.to_string(), "line 3: Invalid field format: zzz : ccc");
+    }
+
+    #[test]
+    fn skipped_lines() {
+        let s = "■xxx : aaa\nzzz : ccc\n■yyy : bbb\n\n";
+        let (dict, skipped) = parse_with_skipped(s, ParseOptions::default()).unwrap();
+        let headwords = kv_vec(&dict)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(headwords, vec!["xxx", "yyy"]);
+        assert_eq!(
+            skipped
+                .iter()
+                .map(|e| (e.line, e.text.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "zzz : ccc"), (4, "")]
+        );
+
+        let (from_reader, skipped_by_reader) =
+            parse_reader_with_skipped(s.as_bytes(), ParseOptions::default()).unwrap();
+        assert_eq!(kv_vec(&from_reader), kv_vec(&dict));
+        assert_eq!(skipped_by_reader, skipped);
+
+        let validation = validate_reader("■run : 走る\n■run : 駆ける\n\n■walk : 歩く".as_bytes());
+        let validation = validation.unwrap();
+        assert_eq!((validation.headwords, validation.fields), (2, 3));
+        assert_eq!(validation.skipped.len(), 1);
+        assert!(!validation.is_clean());
+        assert!(validate_reader("■run : 走る".as_bytes())
+            .unwrap()
+            .is_clean());
+    }
+
+    #[test]
+    fn pronunciation() {
+        let s = "■adjust {他動} : 調節する◆【発音】ədʒʌ́st、【カナ】アジャスト\n■judge {名} : 【音声】dʒʌ́dʒ、裁判官\n■xxx : aaa◆【発音】bbb";
+        let dict = parse(s).unwrap();
+        let exp = |word: &str| search(&dict, word, 0).unwrap()[0].1[0].explanation.clone();
+
+        let adjust = exp("adjust");
+        assert_eq!(adjust.pronunciation.as_deref(), Some("ədʒʌ́st"));
+        assert_eq!(adjust.body, "調節する");
+        assert_eq!(adjust.complements[0].body, "【カナ】アジャスト");
+
+        let judge = exp("judge");
+        assert_eq!(judge.pronunciation.as_deref(), Some("dʒʌ́dʒ"));
+        assert_eq!(judge.body, "裁判官");
+
+        let xxx = exp("xxx");
+        assert_eq!(xxx.pronunciation.as_deref(), Some("bbb"));
+        assert!(xxx.complements.is_empty());
+    }
+
+    #[test]
+    fn senses() {
+        assert_eq!(split_senses("走る"), vec!["走る"]);
+        assert_eq!(split_senses("①走る②逃げる"), vec!["①走る", "②逃げる"]);
+        assert_eq!(
+            split_senses("《動》①走る、②逃げる"),
+            vec!["《動》", "①走る、", "②逃げる"]
+        );
+        assert_eq!(
+            split_senses("1. 走る 2. 逃げる"),
+            vec!["1. 走る", "2. 逃げる"]
+        );
+        assert_eq!(split_senses("3.5 キロ"), vec!["3.5 キロ"]);
+
+        let dict = parse("■run {動} : 【レベル】1①走る②逃げる").unwrap();
+        let exp = &dict.get("run").unwrap()[0].explanation;
+        assert_eq!(exp.body, "①走る②逃げる");
+        assert_eq!(exp.senses, vec!["①走る", "②逃げる"]);
+    }
+
+    #[test]
+    fn whitespace() {
+        let s = "■run {動} :   走る 、  駆ける  ◆ 【変化】 ran, run  ■・Run,  Forrest! : 走れ";
+        let dict = parse(s).unwrap();
+        let f = &dict.get("run").unwrap()[0];
+        assert_eq!(f.explanation.body, "走る 、 駆ける");
+        assert_eq!(f.explanation.complements[0].body, "【変化】 ran, run");
+        assert_eq!(f.examples[0].sentence, "・Run,  Forrest! : 走れ");
+    }
+
+    #[test]
+    fn display() {
+        let dict = parse("■run {動} : 走る◆【変化】ran◆【発音】rʌ́n■・Run! 走れ！\n■runner : 走者")
+            .unwrap();
+        let run = &dict.get("run").unwrap()[0];
+        assert_eq!(run.explanation.to_string(), "走る◆【変化】ran");
+        assert_eq!(
+            run.to_string(),
+            "{動} : 走る◆【変化】ran\n    【発音】rʌ́n\n        Run!\n        走れ！"
+        );
+        assert_eq!(run.header("run"), "■run {動}");
+        let runner = &dict.get("runner").unwrap()[0];
+        assert_eq!(runner.to_string(), "走者");
+        assert_eq!(runner.header("runner"), "■runner");
+    }
+
+    #[test]
+    fn see_also() {
+        let s = "■give up : あきらめる◆→give in; surrender\n■kid : 子ども、⇒child\n■lad : 少年◆￫boy; youth◆-> kid\n■arrow : 矢";
+        let dict = parse(s).unwrap();
+        let field = |k: &str| dict.get(k).unwrap()[0].clone();
+        let give_up = field("give up");
+        assert_eq!(give_up.see_also, vec!["give in", "surrender"]);
+        assert!(give_up.explanation.complements.is_empty());
+        assert_eq!(give_up.to_string(), "あきらめる\n    → give in, surrender");
+        let kid = field("kid");
+        assert_eq!(kid.see_also, vec!["child"]);
+        assert_eq!(kid.explanation.body, "子ども");
+        assert_eq!(field("lad").see_also, vec!["boy", "youth", "kid"]);
+        assert!(field("arrow").see_also.is_empty());
+    }
+
+    #[test]
+    fn see_also_mid_body() {
+        let s = "■morph : A -> B の変化、転じて変身\n■shift : 移動、→move。転じて変化（比喩）";
+        let dict = parse(s).unwrap();
+        let morph = &dict.get("morph").unwrap()[0];
+        assert!(morph.see_also.is_empty());
+        assert_eq!(morph.explanation.body, "A -> B の変化、転じて変身");
+        let shift = &dict.get("shift").unwrap()[0];
+        assert_eq!(shift.see_also, vec!["move"]);
+        assert_eq!(shift.explanation.body, "移動。転じて変化（比喩）");
+    }
+
+    #[test]
+    fn raw_line() {
+        let line = "■run  {動} :  走る◆【発音】rʌ́n";
+        assert!(parse(line).unwrap().get("run").unwrap()[0].raw.is_none());
+        let opts = ParseOptions { keep_raw: true };
+        let dict = parse_with(line, opts).unwrap();
+        let field = &dict.get("run").unwrap()[0];
+        assert_eq!(field.raw.as_deref(), Some(line));
+        let dict = parse_reader_with(line.as_bytes(), opts).unwrap();
+        assert_eq!(dict.get("run").unwrap()[0].raw.as_deref(), Some(line));
+    }
+
+    #[test]
+    fn level() {
+        let s = "■adjust {他動} : 調節する◆【レベル】4、【発音】ədʒʌ́st、【カナ】アジャスト\n■cat {名} : 【レベル】1猫\n■xxx : aaa";
+        let dict = parse(s).unwrap();
+        let field = |word: &str| search(&dict, word, 0).unwrap()[0].1[0].clone();
+
+        let adjust = field("adjust");
+        assert_eq!(adjust.level, Some(4));
+        assert_eq!(adjust.explanation.pronunciation.as_deref(), Some("ədʒʌ́st"));
+        assert_eq!(adjust.explanation.complements[0].body, "【カナ】アジャスト");
+
+        let cat = field("cat");
+        assert_eq!(cat.level, Some(1));
+        assert_eq!(cat.explanation.body, "猫");
+
+        assert_eq!(field("xxx").level, None);
+    }
+
+    #[test]
+    fn rank() {
+        let s = "■run {動} : 【ランク】12、走る\n■run {名} : 走ること◆【頻度】 300\n■xxx : aaa";
+        let dict = parse(s).unwrap();
+        let run = dict.get("run").unwrap();
+        assert_eq!(
+            (run[0].rank, run[0].explanation.body.as_str()),
+            (Some(12), "走る")
+        );
+        assert_eq!(run[1].rank, Some(300));
+        assert!(run[1].explanation.complements.is_empty());
+        assert_eq!(dict.get("xxx").unwrap()[0].rank, None);
+    }
+
+    #[test]
+    fn dedup_fields() {
+        let s = "■xxx : aaa■ddd\n■xxx : aaa■ddd\n■yyy : bbb\n■xxx : aaa■ddd\n■xxx : aaa";
+        let dict = parse(s).unwrap();
+        assert_eq!(
+            kv_vec(&dict),
+            vec![
+                (
+                    "xxx".to_string(),
+                    &vec![
+                        new_field(None, "aaa", vec![], vec![("ddd", vec![])]),
+                        new_field(None, "aaa", vec![], vec![]),
+                    ]
+                ),
+                (
+                    "yyy".to_string(),
+                    &vec![new_field(None, "bbb", vec![], vec![])]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn source_order() {
+        // Deliberately not in the order sorting the fields would give.
+        let s = "■run {動} : 走る\n■walk : 歩く\n■run {名} : 走ること\n■run {動} : 運営する\n■run {動} : 走る\n■run {形} : 溶けた";
+        let bodies = |dict: &Dict| {
+            dict.get("run")
+                .unwrap()
+                .iter()
+                .map(|f| f.explanation.body.clone())
+                .collect::<Vec<_>>()
+        };
+        let expected = vec!["走る", "走ること", "運営する", "溶けた"];
+        let dict = parse(s).unwrap();
+        assert_eq!(bodies(&dict), expected);
+        assert_eq!(bodies(&parse_reader(s.as_bytes()).unwrap()), expected);
+        let bytes = dict.to_bytes().unwrap();
+        let loaded = Dict::from_bytes(&bytes.keys, &bytes.folded, &bytes.fields).unwrap();
+        assert_eq!(bodies(&loaded), expected);
+    }
+
+    #[test]
+    fn example_halves() {
+        let halves = |s: &str| {
+            let e = Example::new(s.to_string(), vec![]);
+            (e.english, e.japanese)
+        };
+        assert_eq!(
+            halves("・There was an awkward silence for a moment. ちょっとの間、気まずい沈黙がありました。"),
+            (
+                Some("There was an awkward silence for a moment.".to_string()),
+                Some("ちょっとの間、気まずい沈黙がありました。".to_string())
+            )
+        );
+        assert_eq!(
+            halves("・Hello, world."),
+            (Some("Hello, world.".to_string()), None)
+        );
+        assert_eq!(
+            halves("・気まずい沈黙"),
+            (None, Some("気まずい沈黙".to_string()))
+        );
+    }
+
     #[test]
     fn serde() {
         let s = "■xxx : aaa◆bbb◆ccc■ddd◆eee■fff";
@@ -327,4 +2277,579 @@ mod tests {
             )]
         )
     }
+
+    #[test]
+    fn serde_round_trip() {
+        let s = "■Run {動} : 【発音】rʌ'n、走る→walk◆速く■・Run! 走れ！\n\
+                 ■run up : 駆け上がる\n■run-up : 助走\n■walk : 【レベル】1、歩く";
+        let mut dict = parse(s).unwrap();
+        dict.source = Some(SourceStamp {
+            modified: 1_600_000_000_000_000_000,
+            len: s.len() as u64,
+        });
+        let bytes = bincode::serialize(&dict).unwrap();
+        let loaded: Dict = bincode::deserialize(&bytes).unwrap();
+
+        // The FSTs are stored as their raw bytes, so they come back unchanged.
+        assert_eq!(
+            loaded.keys.as_fst().as_bytes(),
+            dict.keys.as_fst().as_bytes()
+        );
+        assert_eq!(
+            loaded.folded.keys.as_fst().as_bytes(),
+            dict.folded.keys.as_fst().as_bytes()
+        );
+        assert_eq!(kv_vec(&loaded), kv_vec(&dict));
+        assert_eq!(loaded.folded.groups.list(), dict.folded.groups.list());
+        assert_eq!(loaded.reverse.map(), dict.reverse.map());
+        assert_eq!(loaded.phrases.map(), dict.phrases.map());
+        assert_eq!(loaded.source, dict.source);
+    }
+
+    #[test]
+    fn search_distance() {
+        let s = "■xxx : aaa\n■xxy : bbb\n■yyy : ccc";
+        let dict = parse(s).unwrap();
+        let keys = |v: Vec<(String, &[Field])>| v.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(search(&dict, "xxx", 0).unwrap()), vec!["xxx"]);
+        assert_eq!(keys(search(&dict, "xxx", 1).unwrap()), vec!["xxx", "xxy"]);
+        assert!(search(&dict, "zzz", 0).unwrap().is_empty());
+
+        let found = search(&dict, "xxy", 0).unwrap();
+        assert_eq!(found[0].1, &[new_field(None, "bbb", vec![], vec![])][..]);
+    }
+
+    #[test]
+    fn part_of_speech() {
+        let s = "■run {自動-1} : 走る\n■run {名} : 走ること\n■awkward silence {1} : 沈黙";
+        let dict = parse(s).unwrap();
+        let pos = |word: &str| {
+            let (_, fields) = &search(&dict, word, 0).unwrap()[0];
+            let mut pos = fields.iter().map(|f| f.pos.clone()).collect::<Vec<_>>();
+            pos.sort();
+            pos
+        };
+        assert_eq!(
+            pos("run"),
+            vec![Some(PartOfSpeech::Noun), Some(PartOfSpeech::Verb)]
+        );
+        assert_eq!(
+            pos("awkward silence"),
+            vec![Some(PartOfSpeech::Other("1".to_string()))]
+        );
+        assert_eq!(PartOfSpeech::from_ident("名-複"), PartOfSpeech::Noun);
+    }
+
+    #[test]
+    fn search_paged() {
+        let s = "■ran : 走った\n■rat : ネズミ\n■run : 走る\n■rut : わだち\n■xyz : なし";
+        let dict = parse(s).unwrap();
+        let page = |offset, limit| {
+            let page = search_page(&dict, "rat", 1, offset, limit).unwrap();
+            let keys = page.results.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+            (page.total, keys)
+        };
+        assert_eq!(page(0, 2), (3, vec!["ran".to_string(), "rat".to_string()]));
+        assert_eq!(page(2, 2), (3, vec!["rut".to_string()]));
+        assert_eq!(page(5, 2), (3, vec![]));
+    }
+
+    #[test]
+    fn char_distance() {
+        assert_eq!(levenshtein_chars("run", "ran"), 1);
+        assert_eq!(levenshtein_chars("run", "running"), 4);
+        assert_eq!(levenshtein_chars("", "abc"), 3);
+        assert_eq!(levenshtein_chars("走る", "走った"), 2);
+        assert_eq!(levenshtein_chars("走る", "走る"), 0);
+        // One character, however many bytes it takes in UTF-8.
+        assert_eq!(levenshtein_chars("猫", "犬"), 1);
+    }
+
+    #[test]
+    fn permalink() {
+        let dict = parse("■C# : シーシャープ\n■run : 走る\n■run {名} : 走ること").unwrap();
+        let fields = dict.get("run").unwrap();
+        assert_eq!(dict.field_position("run", &fields[1]), Some(1));
+        let id = field_id("run", 1);
+        assert_eq!(id, "run#2");
+        assert_eq!(dict.get_by_id(&id), Some(("run", &fields[1])));
+        assert_eq!(dict.get_by_id("C##1").unwrap().0, "C#");
+        assert!(dict.get_by_id("run#0").is_none());
+        assert!(dict.get_by_id("run#3").is_none());
+        assert!(dict.get_by_id("run").is_none());
+        assert!(dict.get_by_id("walk#1").is_none());
+    }
+
+    #[test]
+    fn levenshtein_cache() {
+        let a = levenshtein("cachedword", 1).unwrap();
+        assert!(Arc::ptr_eq(&a, &levenshtein("cachedword", 1).unwrap()));
+        assert!(!Arc::ptr_eq(&a, &levenshtein("cachedword", 2).unwrap()));
+        for i in 0..LEVENSHTEIN_CACHE_LEN {
+            levenshtein(&format!("filler{}", i), 1).unwrap();
+        }
+        assert!(!Arc::ptr_eq(&a, &levenshtein("cachedword", 1).unwrap()));
+
+        let dict = parse("■ran : 走った\n■run : 走る").unwrap();
+        for _ in 0..2 {
+            assert_eq!(search(&dict, "run", 1).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn from_entries() {
+        let run = new_field(Some("動"), "走る", vec![], vec![]);
+        let run_noun = new_field(Some("名"), "走ること", vec![], vec![]);
+        let walk = new_field(None, "歩く", vec![], vec![]);
+        let entries = || {
+            vec![
+                ("walk".to_string(), vec![walk.clone()]),
+                ("run".to_string(), vec![run.clone()]),
+                ("run".to_string(), vec![run.clone(), run_noun.clone()]),
+            ]
+        };
+
+        assert!(Dict::new_from_entries(entries()).is_err());
+        let dict = Dict::new_from_entries(entries().into_iter().skip(2)).unwrap();
+        assert_eq!(dict.get("run").unwrap(), &[run.clone(), run_noun.clone()]);
+        assert!(!dict.contains("walk"));
+
+        let dict = Dict::new_from_entries_with(entries(), DuplicateKeys::Merge).unwrap();
+        assert_eq!(
+            kv_vec(&dict),
+            vec![
+                ("run".to_string(), &vec![run, run_noun]),
+                ("walk".to_string(), &vec![walk]),
+            ]
+        );
+        assert_eq!(reverse_search(&dict, "歩く").len(), 1);
+    }
+
+    #[test]
+    fn stems() {
+        let has = |word: &str, base: &str| stem_candidates(word).iter().any(|c| c == base);
+        assert!(has("running", "run"));
+        assert!(has("walked", "walk"));
+        assert!(has("stopped", "stop"));
+        assert!(has("liked", "like"));
+        assert!(has("making", "make"));
+        assert!(has("studies", "study"));
+        assert!(has("tried", "try"));
+        assert!(has("boxes", "box"));
+        assert!(has("apples", "apple"));
+        assert!(has("Running", "run"));
+        assert_eq!(stem_candidates("walked")[0], "walk");
+        assert_eq!(stem_candidates("running")[0], "run");
+        assert_eq!(stem_candidates("hoping")[0], "hope");
+        assert_eq!(stem_candidates("caring")[0], "care");
+        assert_eq!(stem_candidates("rated")[0], "rate");
+        assert_eq!(stem_candidates("hopes")[0], "hope");
+        assert_eq!(stem_candidates("uses")[0], "use");
+        assert_eq!(stem_candidates("used")[0], "use");
+        assert_eq!(stem_candidates("boxes")[0], "box");
+        assert_eq!(stem_candidates("passed")[0], "pass");
+        assert_eq!(stem_candidates("eating")[0], "eat");
+        assert!(stem_candidates("run").is_empty());
+        assert!(stem_candidates("glass").is_empty());
+        assert!(stem_candidates("is").is_empty());
+    }
+
+    #[test]
+    fn search_prefix() {
+        let s = "■run : 走る\n■runner : 走者\n■rerun : 再放送\n■running : 走ること";
+        let dict = parse(s).unwrap();
+        let keys = prefix_search(&dict, "run")
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["run", "runner", "running"]);
+        assert!(prefix_search(&dict, "walk").unwrap().is_empty());
+    }
+
+    #[test]
+    fn visit_matches() {
+        let s = "■run : 走る
+■Runner : 走者
+■rerun : 再放送
+■running : 走ること";
+        let dict = parse(s).unwrap();
+        let visited = |pattern, ignore_case, max: usize| {
+            let mut keys = Vec::new();
+            for_each_match(&dict, pattern, ignore_case, |key, _| {
+                keys.push(key);
+                keys.len() < max
+            })
+            .unwrap();
+            keys
+        };
+        assert_eq!(
+            visited(KeyPattern::Prefix("run"), false, 10),
+            vec!["run", "running"]
+        );
+        assert_eq!(
+            visited(KeyPattern::Prefix("RUN"), true, 10),
+            vec!["run", "Runner", "running"]
+        );
+        // Returning false stops the scan.
+        assert_eq!(
+            visited(KeyPattern::Contains("run"), false, 2),
+            vec!["rerun", "run"]
+        );
+        assert_eq!(
+            visited(KeyPattern::Glob("r*n"), false, 10),
+            vec!["rerun", "run"]
+        );
+        assert_eq!(
+            visited(KeyPattern::Regex("RUN.+"), true, 10),
+            vec!["Runner", "running"]
+        );
+        assert!(for_each_match(&dict, KeyPattern::Regex("("), false, |_, _| true).is_err());
+    }
+
+    #[test]
+    fn complete_keys() {
+        let dict = parse("■run : 走る\n■runner : 走者\n■running : 走ること\n■walk : 歩く").unwrap();
+        assert_eq!(
+            prefix_keys(&dict, "run", 10),
+            vec!["run", "runner", "running"]
+        );
+        assert_eq!(prefix_keys(&dict, "run", 2), vec!["run", "runner"]);
+        assert!(prefix_keys(&dict, "x", 10).is_empty());
+    }
+
+    #[test]
+    fn search_glob() {
+        let s = "■computer : コンピューター\n■computers : コンピューター\n■color : 色\n■colour : 色\n■cool : 涼しい";
+        let dict = parse(s).unwrap();
+        let keys = |pattern: &str| {
+            glob_search(&dict, pattern)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("comp*ter"), vec!["computer"]);
+        assert_eq!(keys("colo?r"), vec!["colour"]);
+        assert_eq!(
+            keys("co*"),
+            vec!["color", "colour", "computer", "computers", "cool"]
+        );
+        assert_eq!(keys("color"), vec!["color"]);
+        assert_eq!(keys("c.lor"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn search_contains() {
+        let s = "■a.b : 点\n■aab : 二\n■Bacon : ベーコン\n■cab : タクシー\n■crab : カニ";
+        let dict = parse(s).unwrap();
+        let keys = |needle: &str, limit| {
+            contains_search(&dict, needle, limit)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("ab", None), vec!["aab", "cab", "crab"]);
+        assert_eq!(keys("ab", Some(2)), vec!["aab", "cab"]);
+        assert_eq!(keys(".", None), vec!["a.b"]);
+        assert_eq!(keys("ba", None), Vec::<String>::new());
+        let folded = contains_search_ignore_case(&dict, "BA", None).unwrap();
+        assert_eq!(folded[0].0, "Bacon");
+    }
+
+    #[test]
+    fn search_body() {
+        let s = "■dash : 〈話〉走る◆Slang\n■jog : 軽く走る\n■run : 走る\n■walk : 歩く";
+        let dict = parse(s).unwrap();
+        let keys = |results: Vec<(String, &[Field])>| {
+            results.into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(body_search(&dict, "走る", None)),
+            vec!["dash", "jog", "run"]
+        );
+        assert_eq!(
+            keys(body_search(&dict, "走る", Some(2))),
+            vec!["dash", "jog"]
+        );
+        assert_eq!(keys(body_search(&dict, "Slang", None)), vec!["dash"]);
+        assert!(body_search(&dict, "slang", None).is_empty());
+        assert_eq!(
+            keys(body_search_ignore_case(&dict, "slang", None)),
+            vec!["dash"]
+        );
+        assert!(body_search(&dict, "泳ぐ", None).is_empty());
+    }
+
+    #[test]
+    fn search_regex() {
+        let s = "■color : 色\n■colour : 色\n■Colorado : コロラド\n■run : 走る\n■runner : 走者";
+        let dict = parse(s).unwrap();
+        let keys = |pattern: &str, limit| {
+            regex_search(&dict, pattern, limit)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("colou?r", None), vec!["color", "colour"]);
+        assert_eq!(keys("run", None), vec!["run"]);
+        assert_eq!(keys("run.*", None), vec!["run", "runner"]);
+        assert_eq!(keys("[a-z]+", Some(2)), vec!["color", "colour"]);
+        assert!(regex_search(&dict, "col(or", None).is_err());
+        let folded = regex_search_ignore_case(&dict, "COLO\\w+", None).unwrap();
+        assert!(folded.iter().any(|(k, _)| k == "Colorado"));
+    }
+
+    #[test]
+    fn invalid_utf8_key() {
+        let bytes = parse("■cafe : カフェ").unwrap().to_bytes().unwrap();
+        let mut keys = MapBuilder::memory();
+        keys.insert(b"caf\xff", 0).unwrap();
+        let keys = keys.into_inner().unwrap();
+        let dict = Dict::from_bytes(&keys, &bytes.folded, &bytes.fields).unwrap();
+
+        let results = prefix_search(&dict, "caf").unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "caf\u{fffd}");
+        assert_eq!(results[0].1[0].explanation.body, "カフェ");
+    }
+
+    #[test]
+    fn phrase() {
+        let s = "■look up : 調べる\n■look-up : 検索\n■lookup : ルックアップ\n■well-being : 幸福";
+        let dict = parse(s).unwrap();
+        let keys = |phrase: &str| {
+            phrase_search(&dict, phrase)
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        };
+        let all = vec!["look up", "look-up", "lookup"];
+        assert_eq!(keys("lookup"), all);
+        assert_eq!(keys("look  up"), all);
+        assert_eq!(keys("look-up"), all);
+        assert_eq!(keys("wellbeing"), vec!["well-being"]);
+        assert_eq!(keys("well being"), vec!["well-being"]);
+        assert!(keys("look").is_empty());
+
+        let bytes = dict.to_bytes().unwrap();
+        let loaded = Dict::from_bytes(&bytes.keys, &bytes.folded, &bytes.fields).unwrap();
+        assert_eq!(phrase_search(&loaded, "look up").len(), 3);
+    }
+
+    #[test]
+    fn inconsistent_dict() {
+        let mut dict = parse("■run : 走る\n■runner : 走者").unwrap();
+        let runner = dict.get("runner").unwrap().to_vec();
+        // Only the first key still has fields behind it.
+        dict.fields = Fields::from(vec![dict.fields[0].clone()]);
+
+        assert!(dict.fields_for(0).is_some());
+        assert!(dict.fields_for(1).is_none());
+        assert!(dict.get("runner").is_none());
+        assert_eq!(prefix_search(&dict, "run").unwrap().len(), 1);
+        assert_eq!(search_ignore_case(&dict, "RUNNER", 0).unwrap().len(), 0);
+        assert_eq!(dict.entries().count(), 1);
+        assert!(reverse_search(&dict, &runner[0].explanation.body).is_empty());
+    }
+
+    #[test]
+    fn merge() {
+        let mut dict = parse("■run : 走る\n■walk : 歩く").unwrap();
+        let extra = parse("■run : 走る\n■run {名} : 走ること\n■jog : ジョギングする").unwrap();
+        dict.merge(extra).unwrap();
+
+        let bodies = |word: &str| {
+            dict.get(word)
+                .unwrap()
+                .iter()
+                .map(|f| f.explanation.body.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bodies("run"), vec!["走る", "走ること"]);
+        assert_eq!(bodies("jog"), vec!["ジョギングする"]);
+        assert_eq!(bodies("walk"), vec!["歩く"]);
+        assert_eq!(dict.entries().count(), 3);
+        assert_eq!(search_ignore_case(&dict, "JOG", 0).unwrap().len(), 1);
+        assert_eq!(reverse_search(&dict, "走ること")[0].0, "run");
+    }
+
+    #[test]
+    fn get() {
+        let s = "■run : 走る\n■runner : 走者";
+        let dict = parse(s).unwrap();
+        assert_eq!(dict.get("run").unwrap()[0].explanation.body, "走る");
+        assert_eq!(dict.get("runner").unwrap().len(), 1);
+        assert!(dict.get("ru").is_none());
+        assert!(dict.get("walk").is_none());
+        assert!(dict.contains("run"));
+        assert!(!dict.contains("ru"));
+    }
+
+    #[test]
+    fn entries() {
+        let s = "■apple : りんご\n■banana : バナナ\n■cherry : さくらんぼ";
+        let dict = parse(s).unwrap();
+        let all = dict.entries().collect::<Vec<_>>();
+        assert_eq!(
+            all.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>(),
+            vec!["apple", "banana", "cherry"]
+        );
+        assert_eq!(all[1].1[0].explanation.body, "バナナ");
+        assert_eq!(dict.entries().take(2).count(), 2);
+    }
+
+    #[test]
+    fn ignore_case() {
+        let s = "■apple : りんご\n■Apple : アップル社\n■APPLY : 適用する\n■banana : バナナ";
+        let dict = parse(s).unwrap();
+        let keys = |results: Vec<(String, &[Field])>| {
+            results.into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        };
+        assert!(search(&dict, "APPLE", 0).unwrap().is_empty());
+        assert_eq!(
+            keys(search_ignore_case(&dict, "APPLE", 0).unwrap()),
+            vec!["Apple", "apple"]
+        );
+        assert_eq!(
+            keys(prefix_search_ignore_case(&dict, "Appl").unwrap()),
+            vec!["Apple", "apple", "APPLY"]
+        );
+        assert_eq!(
+            keys(glob_search_ignore_case(&dict, "APP?Y").unwrap()),
+            vec!["APPLY"]
+        );
+        let dict: Dict = bincode::deserialize(&bincode::serialize(&dict).unwrap()).unwrap();
+        assert_eq!(
+            keys(search_ignore_case(&dict, "Banana", 0).unwrap()),
+            vec!["banana"]
+        );
+    }
+
+    #[test]
+    fn reverse() {
+        let s = "■run {動} : 走る、駆ける\n■dash {動} : 〈話〉走る\n■walk {動} : 歩く";
+        let dict = parse(s).unwrap();
+        let found = reverse_search(&dict, "走る")
+            .into_iter()
+            .map(|(k, f)| (k, f.explanation.body.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![("dash", "〈話〉走る"), ("run", "走る、駆ける")]);
+        assert!(reverse_search(&dict, "泳ぐ").is_empty());
+    }
+
+    #[test]
+    fn tokenize() {
+        assert_eq!(
+            tokenize_explanation("《コ》〔入力文字の〕自動補完、オートコンプリート"),
+            vec!["オートコンプリート", "自動補完"]
+        );
+        assert_eq!(
+            tokenize_explanation("気まずい［ぎこちない］沈黙"),
+            vec!["気まずい沈黙"]
+        );
+    }
+
+    #[test]
+    fn from_bytes() {
+        let s = "■run {動} : 走る、駆ける\n■Run : ラン";
+        let dict = parse(s).unwrap();
+        let bytes = dict.to_bytes().unwrap();
+        let loaded = Dict::from_bytes(&bytes.keys, &bytes.folded, &bytes.fields).unwrap();
+        assert_eq!(kv_vec(&loaded), kv_vec(&dict));
+        assert_eq!(
+            search_ignore_case(&loaded, "RUN", 0).unwrap(),
+            search_ignore_case(&dict, "RUN", 0).unwrap()
+        );
+        assert!(Dict::from_bytes(&bytes.keys, &bytes.folded, b"garbage").is_err());
+        // The phrase index is the last section; a damaged index fails the
+        // load rather than coming back empty.
+        let mut damaged = bytes.fields.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let err = Dict::from_bytes(&bytes.keys, &bytes.folded, &damaged).unwrap_err();
+        assert!(err.to_string().contains("corrupt"));
+
+        // An entry offset near `u64::MAX` is rejected, not an overflow.
+        let mut damaged = bytes.fields.clone();
+        let at = store::HEADER_LEN + 8;
+        damaged[at..at + 8].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        let loaded = Dict::from_bytes(&bytes.keys, &bytes.folded, &damaged).unwrap();
+        assert!(loaded.get("run").is_none());
+        assert!(loaded.get("Run").is_none());
+        // So is a section offset that cannot be a position in the file.
+        let mut damaged = bytes.fields.clone();
+        damaged[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        let err = Dict::from_bytes(&bytes.keys, &bytes.folded, &damaged).unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+        // Serializing it fails instead of writing a short sequence.
+        assert!(bincode::serialize(&loaded).is_err());
+        assert!(bincode::serialize(&dict).is_ok());
+    }
+
+    #[test]
+    fn memory_usage() {
+        let s = "■run {動} : 走る、駆ける\n■walk : 歩く";
+        let dict = parse(s).unwrap();
+        let usage = dict.memory_usage();
+        assert_eq!(usage.keys, dict.keys.as_fst().as_bytes().len());
+        assert!(usage.folded_keys > 0 && !usage.mapped);
+        assert!(usage.fields >= "走る、駆ける".len() + "歩く".len());
+        assert_eq!(usage.fields_store, 0);
+
+        let bytes = dict.to_bytes().unwrap();
+        let loaded = Dict::from_bytes(&bytes.keys, &bytes.folded, &bytes.fields).unwrap();
+        let before = loaded.memory_usage();
+        assert_eq!(before.fields_store, bytes.fields.len());
+        loaded.get("run").unwrap();
+        assert!(loaded.memory_usage().fields > before.fields + "走る、駆ける".len());
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn save_load() {
+        let s = "■run {動} : 走る、駆ける\n■xxx : aaa◆bbb◆ccc■ddd◆eee■fff";
+        let mut dict = parse(s).unwrap();
+        dict.source = Some(SourceStamp {
+            modified: 1_600_000_000_000_000_000,
+            len: s.len() as u64,
+        });
+        let dir = std::env::temp_dir().join(format!("eijiro-save-{}", std::process::id()));
+        dict.save(&dir).unwrap();
+
+        let loaded = Dict::load(&dir).unwrap();
+        assert_eq!(loaded.source, dict.source);
+        assert_eq!(kv_vec(&loaded), kv_vec(&dict));
+        assert_eq!(loaded.reverse.map(), dict.reverse.map());
+        assert_eq!(loaded.folded.groups.list(), dict.folded.groups.list());
+        assert_eq!(
+            search_ignore_case(&loaded, "RUN", 0).unwrap(),
+            search_ignore_case(&dict, "RUN", 0).unwrap()
+        );
+        assert!(loaded.fields.get(2).is_none());
+
+        // Saving over a mapped cache replaces the files instead of
+        // truncating them, so `loaded` still sees its own contents.
+        parse("■walk : 歩く").unwrap().save(&dir).unwrap();
+        assert_eq!(kv_vec(&loaded), kv_vec(&dict));
+        assert!(Dict::load(&dir).unwrap().contains("walk"));
+        let mut files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, [FIELDS_FILE, FOLDED_FILE, KEYS_FILE]);
+
+        let fields_path = dir.join(FIELDS_FILE);
+        let mut bytes = std::fs::read(&fields_path).unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION - 1).to_le_bytes());
+        std::fs::write(&fields_path, &bytes).unwrap();
+        let err = Dict::load(&dir).unwrap_err();
+        assert!(err.to_string().contains("outdated"));
+
+        std::fs::write(&fields_path, b"garbage").unwrap();
+        assert!(Dict::load(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,650 @@
+use crate::{Field, ReverseEntry, SourceStamp, FORMAT_VERSION};
+use anyhow::{anyhow, ensure, Result};
+use log::warn;
+#[cfg(feature = "fs")]
+use memmap2::Mmap;
+use serde::de::{DeserializeOwned, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Error as _, SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::io::Write;
+#[cfg(feature = "fs")]
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// Backing bytes for the FSTs and the fields store, either owned or
+/// memory-mapped from disk.
+#[derive(Debug, Clone)]
+pub enum Storage {
+    Owned(Vec<u8>),
+    #[cfg(feature = "fs")]
+    Mapped(Arc<Mmap>),
+}
+
+impl Storage {
+    /// Whether the bytes are mapped from a file rather than held in memory.
+    pub fn is_mapped(&self) -> bool {
+        match self {
+            Storage::Owned(_) => false,
+            #[cfg(feature = "fs")]
+            Storage::Mapped(_) => true,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Storage {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Storage::Owned(bytes) => bytes,
+            #[cfg(feature = "fs")]
+            Storage::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+pub(crate) fn map_file(path: &Path) -> Result<Storage> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: cache files are only ever replaced by rename (`Dict::save`,
+    // `replace_file`), never modified in place, so a mapped file keeps its
+    // contents for as long as the map lives.
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(Storage::Mapped(Arc::new(mmap)))
+}
+
+/// Field lists indexed by the values stored in `Dict::keys`.
+///
+/// Fields backed by a store decode each entry on first access, so opening a
+/// large dictionary does not touch the fields of words that are never looked up.
+#[derive(Debug, Clone)]
+pub struct Fields {
+    repr: FieldsRepr,
+}
+
+#[derive(Debug, Clone)]
+enum FieldsRepr {
+    Memory(Vec<Vec<Field>>),
+    /// Backed by a fields store written by `write_store`.
+    Stored {
+        store: Arc<Storage>,
+        layout: StoreLayout,
+        cache: Vec<OnceLock<Vec<Field>>>,
+    },
+}
+
+impl Fields {
+    pub fn len(&self) -> usize {
+        match &self.repr {
+            FieldsRepr::Memory(fields) => fields.len(),
+            FieldsRepr::Stored { cache, .. } => cache.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `None` when `idx` is out of range or the entry fails to decode.
+    pub fn get(&self, idx: usize) -> Option<&Vec<Field>> {
+        match &self.repr {
+            FieldsRepr::Memory(fields) => fields.get(idx),
+            FieldsRepr::Stored {
+                store,
+                layout,
+                cache,
+            } => {
+                let cell = cache.get(idx)?;
+                if let Some(fields) = cell.get() {
+                    return Some(fields);
+                }
+                let fields =
+                    bincode::deserialize(layout.entry(store.as_ref().as_ref(), idx)?).ok()?;
+                Some(cell.get_or_init(|| fields))
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vec<Field>> {
+        (0..self.len()).filter_map(move |idx| self.get(idx))
+    }
+
+    /// Estimated heap bytes of the decoded field lists (see
+    /// `Field::heap_size`). Entries of a store that were never looked up
+    /// cost nothing here.
+    pub fn heap_size(&self) -> usize {
+        let list_size = |fields: &Vec<Field>| {
+            fields.capacity() * std::mem::size_of::<Field>()
+                + fields.iter().map(Field::heap_size).sum::<usize>()
+        };
+        match &self.repr {
+            FieldsRepr::Memory(fields) => {
+                fields.capacity() * std::mem::size_of::<Vec<Field>>()
+                    + fields.iter().map(list_size).sum::<usize>()
+            }
+            FieldsRepr::Stored { cache, .. } => {
+                cache.capacity() * std::mem::size_of::<OnceLock<Vec<Field>>>()
+                    + cache
+                        .iter()
+                        .filter_map(OnceLock::get)
+                        .map(list_size)
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// The fields store backing these fields, if they were opened from one.
+    pub fn store(&self) -> Option<&Storage> {
+        match &self.repr {
+            FieldsRepr::Memory(_) => None,
+            FieldsRepr::Stored { store, .. } => Some(store),
+        }
+    }
+}
+
+impl From<Vec<Vec<Field>>> for Fields {
+    fn from(fields: Vec<Vec<Field>>) -> Self {
+        Fields {
+            repr: FieldsRepr::Memory(fields),
+        }
+    }
+}
+
+impl std::ops::Index<usize> for Fields {
+    type Output = Vec<Field>;
+    fn index(&self, idx: usize) -> &Vec<Field> {
+        self.get(idx)
+            .unwrap_or_else(|| panic!("no fields stored for index {}", idx))
+    }
+}
+
+impl Serialize for Fields {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // Not `iter()`: skipping an entry that fails to decode would leave
+        // fewer elements than the declared length and shift every later one.
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for idx in 0..self.len() {
+            let fields = self.get(idx).ok_or_else(|| {
+                S::Error::custom(format!("failed to read fields for index {}", idx))
+            })?;
+            seq.serialize_element(fields)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct FieldsVisitor;
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a sequence of field lists")
+            }
+            fn visit_seq<V>(self, mut seq: V) -> Result<Fields, V::Error>
+            where
+                V: SeqAccess<'de>,
+            {
+                let mut fields = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(f) = seq.next_element()? {
+                    fields.push(f);
+                }
+                Ok(Fields::from(fields))
+            }
+        }
+        deserializer.deserialize_seq(FieldsVisitor)
+    }
+}
+
+/// Reverse index from explanation tokens to fields, decoded on first use.
+#[derive(Debug, Clone)]
+pub struct Reverse {
+    repr: ReverseRepr,
+}
+
+#[derive(Debug, Clone)]
+enum ReverseRepr {
+    Memory(HashMap<String, Vec<ReverseEntry>>),
+    Stored {
+        store: Arc<Storage>,
+        layout: StoreLayout,
+        index: OnceLock<HashMap<String, Vec<ReverseEntry>>>,
+    },
+}
+
+impl Reverse {
+    pub fn get(&self, token: &str) -> Option<&Vec<ReverseEntry>> {
+        self.map().get(token)
+    }
+
+    pub fn map(&self) -> &HashMap<String, Vec<ReverseEntry>> {
+        match &self.repr {
+            ReverseRepr::Memory(map) => map,
+            ReverseRepr::Stored {
+                store,
+                layout,
+                index,
+            } => index.get_or_init(|| {
+                decode_section(layout.reverse(store.as_ref().as_ref()), "reverse index")
+            }),
+        }
+    }
+}
+
+impl From<HashMap<String, Vec<ReverseEntry>>> for Reverse {
+    fn from(map: HashMap<String, Vec<ReverseEntry>>) -> Self {
+        Reverse {
+            repr: ReverseRepr::Memory(map),
+        }
+    }
+}
+
+impl Serialize for Reverse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.map().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Reverse {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        HashMap::deserialize(deserializer).map(Reverse::from)
+    }
+}
+
+/// Canonical phrase forms (see `canonical_phrase`) -> the headwords that
+/// spell them with spaces or hyphens, decoded on first use.
+#[derive(Debug, Clone)]
+pub struct Phrases {
+    repr: PhrasesRepr,
+}
+
+#[derive(Debug, Clone)]
+enum PhrasesRepr {
+    Memory(HashMap<String, Vec<String>>),
+    Stored {
+        store: Arc<Storage>,
+        layout: StoreLayout,
+        index: OnceLock<HashMap<String, Vec<String>>>,
+    },
+}
+
+impl Phrases {
+    pub fn get(&self, canonical: &str) -> Option<&Vec<String>> {
+        self.map().get(canonical)
+    }
+
+    pub fn map(&self) -> &HashMap<String, Vec<String>> {
+        match &self.repr {
+            PhrasesRepr::Memory(map) => map,
+            PhrasesRepr::Stored {
+                store,
+                layout,
+                index,
+            } => index.get_or_init(|| {
+                decode_section(layout.phrases(store.as_ref().as_ref()), "phrase index")
+            }),
+        }
+    }
+}
+
+impl From<HashMap<String, Vec<String>>> for Phrases {
+    fn from(map: HashMap<String, Vec<String>>) -> Self {
+        Phrases {
+            repr: PhrasesRepr::Memory(map),
+        }
+    }
+}
+
+impl Serialize for Phrases {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.map().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Phrases {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        HashMap::deserialize(deserializer).map(Phrases::from)
+    }
+}
+
+/// Decodes an index section that passed its checksum in `StoreLayout::read`.
+/// Failing now means the cache was written by a broken build; the index is
+/// left empty, which at least is reported.
+fn decode_section<T: DeserializeOwned + Default>(bytes: &[u8], name: &str) -> T {
+    bincode::deserialize(bytes).unwrap_or_else(|e| {
+        warn!(
+            "the {} of the fields store cannot be decoded ({}); rebuild the cache",
+            name, e
+        );
+        T::default()
+    })
+}
+
+/// Original headwords per `FoldedKeys` group, decoded on first use.
+#[derive(Debug, Clone)]
+pub struct Groups {
+    repr: GroupsRepr,
+}
+
+#[derive(Debug, Clone)]
+enum GroupsRepr {
+    Memory(Vec<Vec<Option<String>>>),
+    Stored {
+        store: Arc<Storage>,
+        layout: StoreLayout,
+        list: OnceLock<Vec<Vec<Option<String>>>>,
+    },
+}
+
+impl Groups {
+    pub fn get(&self, idx: usize) -> Option<&Vec<Option<String>>> {
+        self.list().get(idx)
+    }
+
+    pub fn list(&self) -> &Vec<Vec<Option<String>>> {
+        match &self.repr {
+            GroupsRepr::Memory(list) => list,
+            GroupsRepr::Stored {
+                store,
+                layout,
+                list,
+            } => list.get_or_init(|| {
+                decode_section(layout.groups(store.as_ref().as_ref()), "folded groups")
+            }),
+        }
+    }
+}
+
+impl From<Vec<Vec<Option<String>>>> for Groups {
+    fn from(list: Vec<Vec<Option<String>>>) -> Self {
+        Groups {
+            repr: GroupsRepr::Memory(list),
+        }
+    }
+}
+
+impl Serialize for Groups {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.list().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Groups {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::deserialize(deserializer).map(Groups::from)
+    }
+}
+
+// Fields store layout (all integers little-endian):
+//
+//   [u8; 4]   MAGIC
+//   u32       FORMAT_VERSION
+//   u64       number of entries (n)
+//   u64       reverse index offset
+//   u64       reverse index length
+//   u64       folded groups offset
+//   u64       folded groups length
+//   u64       phrase index offset
+//   u64       phrase index length
+//   u64       source mtime, in nanoseconds since the epoch
+//   u64       source length
+//   u64       `checksum` of the reverse index
+//   u64       `checksum` of the folded groups
+//   u64       `checksum` of the phrase index
+//   u64 * n+1 entry offsets
+//   ...       bincode `Vec<Field>` per entry, the bincode reverse index,
+//             the bincode folded groups, then the bincode phrase index
+//
+// Offsets are relative to the end of the header. Both source words are
+// `NO_SOURCE` when the dictionary was not built from a file. The indexes are
+// only decoded on first use, so their checksums are what lets a damaged
+// cache fail `StoreLayout::read` (and get rebuilt) instead of turning into
+// an empty index later.
+const MAGIC: &[u8; 4] = b"EJRD";
+pub(crate) const HEADER_LEN: usize = 4 + 4 + 8 * 12;
+
+/// FNV-1a over 8-byte words, fast enough to run over the index sections on
+/// every load.
+fn checksum(bytes: &[u8]) -> u64 {
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut words = bytes.chunks_exact(8);
+    for word in &mut words {
+        hash = (hash ^ u64::from_le_bytes(word.try_into().unwrap())).wrapping_mul(PRIME);
+    }
+    for &byte in words.remainder() {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(PRIME);
+    }
+    hash
+}
+const NO_SOURCE: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct StoreLayout {
+    len: usize,
+    reverse_offset: usize,
+    reverse_len: usize,
+    groups_offset: usize,
+    groups_len: usize,
+    phrases_offset: usize,
+    phrases_len: usize,
+    source: Option<SourceStamp>,
+}
+
+impl StoreLayout {
+    fn read(store: &[u8]) -> Result<Self> {
+        ensure!(
+            store.len() >= 8 && &store[0..4] == MAGIC,
+            "not an eijiro fields store"
+        );
+        let version = u32::from_le_bytes(store[4..8].try_into().unwrap());
+        ensure!(
+            version == FORMAT_VERSION,
+            "fields store version {} is outdated (expected {})",
+            version,
+            FORMAT_VERSION
+        );
+        ensure!(store.len() >= HEADER_LEN, "fields store is truncated");
+        let word = |i: usize| u64::from_le_bytes(store[8 + i * 8..16 + i * 8].try_into().unwrap());
+        // A size past `usize::MAX` (on 32-bit targets such as wasm32) cannot
+        // fit in the file either, and must not wrap to a small one.
+        let size =
+            |i: usize| usize::try_from(word(i)).map_err(|_| anyhow!("fields store is truncated"));
+        let layout = StoreLayout {
+            len: size(0)?,
+            reverse_offset: size(1)?,
+            reverse_len: size(2)?,
+            groups_offset: size(3)?,
+            groups_len: size(4)?,
+            phrases_offset: size(5)?,
+            phrases_len: size(6)?,
+            source: match (word(7), word(8)) {
+                (NO_SOURCE, NO_SOURCE) => None,
+                (modified, len) => Some(SourceStamp { modified, len }),
+            },
+        };
+        let data_len = store.len() - HEADER_LEN;
+        ensure!(
+            layout.reverse_offset.saturating_add(layout.reverse_len) <= data_len
+                && layout.groups_offset.saturating_add(layout.groups_len) <= data_len
+                && layout.phrases_offset.saturating_add(layout.phrases_len) <= data_len
+                && layout.len.saturating_add(1).saturating_mul(8) <= data_len,
+            "fields store is truncated"
+        );
+        let sections = [
+            (layout.reverse(store), word(9)),
+            (layout.groups(store), word(10)),
+            (layout.phrases(store), word(11)),
+        ];
+        ensure!(
+            sections.iter().all(|(bytes, sum)| checksum(bytes) == *sum),
+            "fields store index is corrupt"
+        );
+        Ok(layout)
+    }
+
+    fn offset(&self, store: &[u8], idx: usize) -> Option<usize> {
+        let at = HEADER_LEN + idx * 8;
+        usize::try_from(u64::from_le_bytes(store[at..at + 8].try_into().unwrap())).ok()
+    }
+
+    fn entry<'a>(&self, store: &'a [u8], idx: usize) -> Option<&'a [u8]> {
+        if idx >= self.len {
+            return None;
+        }
+        // The offsets come from the file; a corrupt one must not overflow.
+        let begin = HEADER_LEN.checked_add(self.offset(store, idx)?)?;
+        let end = HEADER_LEN.checked_add(self.offset(store, idx + 1)?)?;
+        store.get(begin..end)
+    }
+
+    fn reverse<'a>(&self, store: &'a [u8]) -> &'a [u8] {
+        let begin = HEADER_LEN + self.reverse_offset;
+        &store[begin..begin + self.reverse_len]
+    }
+
+    fn groups<'a>(&self, store: &'a [u8]) -> &'a [u8] {
+        let begin = HEADER_LEN + self.groups_offset;
+        &store[begin..begin + self.groups_len]
+    }
+
+    fn phrases<'a>(&self, store: &'a [u8]) -> &'a [u8] {
+        let begin = HEADER_LEN + self.phrases_offset;
+        &store[begin..begin + self.phrases_len]
+    }
+}
+
+pub(crate) fn write_store<W: Write + ?Sized>(
+    out: &mut W,
+    fields: &Fields,
+    reverse: &Reverse,
+    groups: &Groups,
+    phrases: &Phrases,
+    source: Option<SourceStamp>,
+) -> Result<()> {
+    let table_len = (fields.len() + 1) * 8;
+    let mut data = Vec::new();
+    let mut offsets = Vec::with_capacity(fields.len() + 1);
+    for idx in 0..fields.len() {
+        let entry = fields
+            .get(idx)
+            .ok_or_else(|| anyhow!("failed to read fields for index {}", idx))?;
+        offsets.push((table_len + data.len()) as u64);
+        bincode::serialize_into(&mut data, entry)?;
+    }
+    offsets.push((table_len + data.len()) as u64);
+    let reverse_offset = table_len + data.len();
+    bincode::serialize_into(&mut data, reverse.map())?;
+    let reverse_len = table_len + data.len() - reverse_offset;
+    let groups_offset = table_len + data.len();
+    bincode::serialize_into(&mut data, groups.list())?;
+    let groups_len = table_len + data.len() - groups_offset;
+    let phrases_offset = table_len + data.len();
+    bincode::serialize_into(&mut data, phrases.map())?;
+    let phrases_len = table_len + data.len() - phrases_offset;
+
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    for word in &[
+        fields.len(),
+        reverse_offset,
+        reverse_len,
+        groups_offset,
+        groups_len,
+        phrases_offset,
+        phrases_len,
+    ] {
+        out.write_all(&(*word as u64).to_le_bytes())?;
+    }
+    let (modified, len) = source.map_or((NO_SOURCE, NO_SOURCE), |s| (s.modified, s.len));
+    out.write_all(&modified.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    for (begin, len) in &[
+        (reverse_offset, reverse_len),
+        (groups_offset, groups_len),
+        (phrases_offset, phrases_len),
+    ] {
+        let section = &data[begin - table_len..begin - table_len + len];
+        out.write_all(&checksum(section).to_le_bytes())?;
+    }
+    for offset in offsets {
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    out.write_all(&data)?;
+    Ok(())
+}
+
+/// The indexes kept in a fields store, as returned by `open_store`.
+pub(crate) struct Stored {
+    pub fields: Fields,
+    pub reverse: Reverse,
+    pub groups: Groups,
+    pub phrases: Phrases,
+    pub source: Option<SourceStamp>,
+}
+
+pub(crate) fn open_store(store: Storage) -> Result<Stored> {
+    let layout = StoreLayout::read(store.as_ref())?;
+    let store = Arc::new(store);
+    let mut cache = Vec::with_capacity(layout.len);
+    cache.resize_with(layout.len, OnceLock::new);
+    let fields = Fields {
+        repr: FieldsRepr::Stored {
+            store: store.clone(),
+            layout,
+            cache,
+        },
+    };
+    let reverse = Reverse {
+        repr: ReverseRepr::Stored {
+            store: store.clone(),
+            layout,
+            index: OnceLock::new(),
+        },
+    };
+    let groups = Groups {
+        repr: GroupsRepr::Stored {
+            store: store.clone(),
+            layout,
+            list: OnceLock::new(),
+        },
+    };
+    let phrases = Phrases {
+        repr: PhrasesRepr::Stored {
+            store,
+            layout,
+            index: OnceLock::new(),
+        },
+    };
+    Ok(Stored {
+        fields,
+        reverse,
+        groups,
+        phrases,
+        source: layout.source,
+    })
+}
@@ -1,243 +1,3615 @@
-use anyhow::{anyhow, ensure, Result};
-use clap::{App, Arg, ArgMatches, SubCommand};
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::{App, Arg, ArgMatches, Shell, SubCommand};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::prelude::*;
+use std::io::{BufReader, IsTerminal};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
 
-use eijiro_parser::{fst, Dict};
-use fst::{IntoStreamer, Streamer};
+use eijiro_parser::fst::{IntoStreamer, Streamer};
+use eijiro_parser::{Dict, Example, Field, KeyPattern, ParseError, ParseOptions, SourceStamp};
+use serde::{Deserialize, Serialize};
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
+use owo_colors::OwoColorize;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+use unicode_normalization::UnicodeNormalization;
 
-fn printer(key: &str, field: &eijiro_parser::Field) -> String {
+/// `println!` into the writer chosen by `--out`. Errors are not checked per
+/// line; `cli_frontend` reports a failing writer when it flushes.
+macro_rules! outln {
+    ($out:expr) => {{
+        let _ = writeln!($out);
+    }};
+    ($out:expr, $($arg:tt)*) => {{
+        let _ = writeln!($out, $($arg)*);
+    }};
+}
+
+/// Wraps `text` in ANSI styling when `color` is set.
+fn paint(text: String, color: bool, style: fn(&String) -> String) -> String {
+    if color {
+        style(&text)
+    } else {
+        text
+    }
+}
+
+/// What `printer` renders besides the explanation body.
+#[derive(Debug, Clone)]
+struct PrintOptions {
+    color: bool,
+    examples: bool,
+    /// Examples shown per field; the rest are summarized in one line.
+    max_examples: Option<usize>,
+    complements: bool,
+    /// `--fields`: print only these parts, tab-separated, in this order.
+    parts: Option<Vec<Part>>,
+    /// Append `Field::raw`, the source line, when the cache kept it.
+    raw: bool,
+}
+
+/// A component of a field that `--fields` can select.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Part {
+    Headword,
+    Pos,
+    Body,
+    Complements,
+    Pronunciation,
+    Examples,
+}
+
+impl Part {
+    const NAMES: &'static [&'static str] = &[
+        "headword",
+        "pos",
+        "body",
+        "complements",
+        "pronunciation",
+        "examples",
+    ];
+
+    fn from_name(name: &str) -> Option<Part> {
+        Some(match name {
+            "headword" => Part::Headword,
+            "pos" => Part::Pos,
+            "body" => Part::Body,
+            "complements" => Part::Complements,
+            "pronunciation" => Part::Pronunciation,
+            "examples" => Part::Examples,
+            _ => return None,
+        })
+    }
+
+    /// Parses a comma-separated `--fields` list.
+    fn parse_list(list: &str) -> Result<Vec<Part>, String> {
+        list.split(',')
+            .map(|name| {
+                let name = name.trim();
+                Part::from_name(name).ok_or_else(|| {
+                    format!(
+                        "unknown field: {} (expected one of {})",
+                        name,
+                        Part::NAMES.join(", ")
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+impl PrintOptions {
+    /// Everything, without ANSI styling.
+    #[cfg(any(feature = "gui", test))]
+    fn plain() -> PrintOptions {
+        PrintOptions {
+            color: false,
+            examples: true,
+            max_examples: None,
+            complements: true,
+            parts: None,
+            raw: false,
+        }
+    }
+}
+
+/// Byte range of `headword` to emphasize for `query`: where `query` occurs
+/// in it, or else the prefix the two share (for fuzzy matches).
+fn match_span(headword: &str, query: &str) -> Option<Range<usize>> {
+    if query.is_empty() {
+        return None;
+    }
+    if let Some(start) = headword.find(query) {
+        return Some(start..start + query.len());
+    }
+    let shared = headword
+        .char_indices()
+        .zip(query.chars())
+        .take_while(|((_, a), b)| a == b)
+        .last()
+        .map(|((i, c), _)| i + c.len_utf8())?;
+    Some(0..shared)
+}
+
+/// `headword` with its `match_span` bolded and underlined when `color` is set.
+fn highlight(headword: &str, query: &str, color: bool) -> String {
+    match match_span(headword, query) {
+        Some(span) if color => format!(
+            "{}{}{}",
+            &headword[..span.start],
+            (&headword[span.clone()]).bold().underline(),
+            &headword[span.end..]
+        ),
+        _ => headword.to_string(),
+    }
+}
+
+/// The examples of `field` that `print` allows.
+fn shown_examples<'a>(field: &'a Field, print: &PrintOptions) -> &'a [Example] {
+    let shown = match (print.examples, print.max_examples) {
+        (false, _) => 0,
+        (true, Some(max)) => max.min(field.examples.len()),
+        (true, None) => field.examples.len(),
+    };
+    &field.examples[..shown]
+}
+
+/// One `--fields` part of `field` on a single line.
+fn render_part(key: &str, field: &Field, part: Part, print: &PrintOptions) -> String {
+    match part {
+        Part::Headword => key.to_string(),
+        Part::Pos => field.ident.clone().unwrap_or_default(),
+        Part::Body => field.explanation.body.clone(),
+        Part::Complements => field
+            .explanation
+            .complements
+            .iter()
+            .map(|c| format!("◆{}", c.body))
+            .collect(),
+        Part::Pronunciation => field.explanation.pronunciation.clone().unwrap_or_default(),
+        Part::Examples => shown_examples(field, print)
+            .iter()
+            .map(|e| e.sentence.as_str())
+            .collect::<Vec<_>>()
+            .join(" / "),
+    }
+}
+
+fn printer(key: &str, field: &Field, print: &PrintOptions) -> String {
+    if let Some(parts) = &print.parts {
+        return parts
+            .iter()
+            .map(|&part| render_part(key, field, part, print))
+            .collect::<Vec<_>>()
+            .join("\t");
+    }
+    let color = print.color;
     let header = match field.ident.as_ref() {
-        Some(head) => format!("{{{}}} : ", head),
+        Some(head) => format!(
+            "{} : ",
+            paint(format!("{{{}}}", head), color, |s| s.cyan().to_string())
+        ),
         None => "".to_string(),
     };
+    let pronunciation = match field.explanation.pronunciation.as_ref() {
+        Some(p) => format!("\n    【発音】{}", p),
+        None => "".to_string(),
+    };
+    let see_also = if field.see_also.is_empty() {
+        "".to_string()
+    } else {
+        format!("\n    → {}", field.see_also.join(", "))
+    };
+    let raw = match field.raw.as_ref().filter(|_| print.raw) {
+        Some(line) => format!(
+            "\n    {}",
+            paint(line.clone(), color, |s| s.yellow().to_string())
+        ),
+        None => "".to_string(),
+    };
+
+    let shown = shown_examples(field, print).len();
+    let mut examples = field.examples[..shown]
+        .iter()
+        .fold("".to_string(), |mut p, e| {
+            let lines = match (&e.english, &e.japanese) {
+                (None, None) => vec![&e.sentence],
+                (english, japanese) => english.iter().chain(japanese.iter()).collect(),
+            };
+            for line in lines {
+                p += &format!(
+                    "\n        {}",
+                    paint(line.clone(), color, |s| s.dimmed().to_string())
+                );
+            }
+            p
+        });
+    if shown > 0 && shown < field.examples.len() {
+        examples += &format!(
+            "\n        (+{} more examples)",
+            field.examples.len() - shown
+        );
+    }
+
+    let senses = &field.explanation.senses;
+    let (header, body) = if senses.len() > 1 {
+        let body = senses.iter().fold("".to_string(), |mut p, s| {
+            p += &format!("\n    {}", s);
+            p
+        });
+        (header.trim_end().to_string(), body)
+    } else {
+        (header, field.explanation.body.clone())
+    };
 
     format!(
-        "{}{}{}{}",
+        "{}{}{}{}{}{}{}",
         header,
-        field.explanation.body,
+        body,
         field
             .explanation
             .complements
             .iter()
+            .filter(|_| print.complements)
             .fold("".to_string(), |mut p, c| {
                 p += &format!("◆{}", c.body);
                 p
             }),
-        field.examples.iter().fold("".to_string(), |mut p, e| {
-            p += &format!("\n        {}", e.sentence);
-            p
-        })
+        pronunciation,
+        see_also,
+        examples,
+        raw
     )
 }
 
-const default_lookup_distance: u32 = 0;
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Json,
+    /// One compact JSON object per line, flushed as it is written. Unranked
+    /// searches write each one as it is found (see `LookupOptions::streams`).
+    JsonLines,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    word: &'a str,
+    pos: Option<&'a str>,
+    body: &'a str,
+    pronunciation: Option<&'a str>,
+    complements: Vec<&'a str>,
+    examples: Vec<JsonExample<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    see_also: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<&'a str>,
+    /// `--show-ids`: the field's permalink.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// The `--dict` it came from, when several are searched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dict: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonExample<'a> {
+    sentence: &'a str,
+    english: Option<&'a str>,
+    japanese: Option<&'a str>,
+    complements: Vec<&'a str>,
+}
 
-fn lookup_word(word: &str, dict: &Dict) {
-    println!("<Search word: [{}]>", word);
-    let matcher = fst::automaton::Levenshtein::new(word, default_lookup_distance).unwrap();
-    let mut stream = dict.keys.search(&matcher).into_stream();
-    while let Some((k, idx)) = stream.next() {
-        let item = std::str::from_utf8(k).unwrap();
-        for f in &dict.fields[idx as usize] {
-            println!("{}", printer(item, f));
+impl<'a> JsonEntry<'a> {
+    fn new(word: &'a str, field: &'a Field) -> Self {
+        JsonEntry {
+            word,
+            pos: field.ident.as_deref(),
+            body: &field.explanation.body,
+            pronunciation: field.explanation.pronunciation.as_deref(),
+            complements: field
+                .explanation
+                .complements
+                .iter()
+                .map(|c| c.body.as_str())
+                .collect(),
+            examples: field
+                .examples
+                .iter()
+                .map(|e| JsonExample {
+                    sentence: &e.sentence,
+                    english: e.english.as_deref(),
+                    japanese: e.japanese.as_deref(),
+                    complements: e.complements.iter().map(|c| c.body.as_str()).collect(),
+                })
+                .collect(),
+            see_also: field.see_also.iter().map(String::as_str).collect(),
+            raw: None,
+            id: None,
+            dict: None,
         }
     }
-}
 
-fn cli_frontend(matches: ArgMatches, dict: Dict) {
-    match matches.value_of("word") {
-        Some(word) => lookup_word(&word, &dict),
-        None => loop {
-            let mut word = String::new();
-            print!("=> ");
-            std::io::stdout().flush().unwrap();
-            std::io::stdin().read_line(&mut word).unwrap();
-            let word = word.trim_end();
-            if word == ":exit" {
-                break;
-            }
-            lookup_word(&word, &dict);
-        },
+    fn with_raw(mut self, field: &'a Field, raw: bool) -> Self {
+        self.raw = field.raw.as_deref().filter(|_| raw);
+        self
+    }
+
+    fn with_id(mut self, id: Option<String>) -> Self {
+        self.id = id;
+        self
+    }
+
+    fn with_dict(mut self, dict: Option<&'a str>) -> Self {
+        self.dict = dict;
+        self
     }
 }
 
-fn gui_frontend(dict: Dict) {
-    use gio::prelude::*;
-    use glib::{Type, Value};
-    use gtk::prelude::*;
-    use gtk::{
-        Application, Builder, CellRendererText, Entry, ListStore, TextView, TreeView,
-        TreeViewColumn, Window,
-    };
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SearchMode {
+    /// Levenshtein search over English headwords.
+    Fuzzy,
+    /// Japanese token search over explanations.
+    Reverse,
+    /// Headwords starting with the query.
+    Prefix,
+    /// Headwords matching a `*`/`?` glob.
+    Glob,
+    /// Headwords containing the query anywhere.
+    Contains,
+    /// Headwords equal to the query ignoring spaces and hyphens.
+    Phrase,
+    /// Headwords matching the query as a regex over the whole key.
+    Regex,
+    /// Headwords whose explanations or complements contain the query.
+    Body,
+}
 
-    let app = Application::new(Some("info.alpha-kai-net.eijiro"), Default::default())
-        .expect("Failed to initialize GTK application");
-    //let glade_file_path = "eijiro.glade";
-    let dict = Rc::new(dict);
-    app.connect_activate(move |app| {
-        let builder = Builder::from_string(include_str!("../eijiro.glade"));
-        let window = builder
-            .get_object::<Window>("window")
-            .expect("Failed to get handle of window");
-        window.connect_delete_event(|_, _| {
-            gtk::main_quit();
-            Inhibit(false)
-        });
+impl SearchMode {
+    const ALL: &'static [SearchMode] = &[
+        SearchMode::Fuzzy,
+        SearchMode::Reverse,
+        SearchMode::Prefix,
+        SearchMode::Glob,
+        SearchMode::Contains,
+        SearchMode::Phrase,
+        SearchMode::Regex,
+        SearchMode::Body,
+    ];
 
-        fn append_word(word: &str, word_list_store: &ListStore, word_column_id: u32) {
-            let iter = word_list_store.insert(-1);
-            word_list_store.set_value(&iter, word_column_id, &word.to_value() as &Value);
+    /// The `mode` parameter of `GET /lookup`.
+    fn name(self) -> &'static str {
+        match self {
+            SearchMode::Fuzzy => "fuzzy",
+            SearchMode::Reverse => "reverse",
+            SearchMode::Prefix => "prefix",
+            SearchMode::Glob => "glob",
+            SearchMode::Contains => "contains",
+            SearchMode::Phrase => "phrase",
+            SearchMode::Regex => "regex",
+            SearchMode::Body => "body",
         }
+    }
 
-        // Setup word_list
-        let word_list = builder
-            .get_object::<TreeView>("word_list")
-            .expect("Failed to get handle of word_list");
-        let (word_list_store, word_column_id) = {
-            // Setup TreeView
-            // TreeView Element Types
-            let column_types = [Type::String];
-            let word_list_store = ListStore::new(&column_types);
-            // Setup first column
-            let word_column = TreeViewColumn::new();
-            let word_column_id: u32 = 0;
-            // Initialize & config column
-            {
-                word_column.set_title("Word");
-                let cell_renderer = CellRendererText::new();
-                word_column.pack_start(&cell_renderer, true);
-                word_column.add_attribute(&cell_renderer, "text", word_column_id as i32);
-                word_list.append_column(&word_column);
-            }
-            word_list.set_model(Some(&word_list_store));
+    fn from_name(name: &str) -> Option<SearchMode> {
+        SearchMode::ALL.iter().copied().find(|m| m.name() == name)
+    }
+}
 
-            (word_list_store, word_column_id)
-        };
-        word_list.set_activate_on_single_click(true);
+#[derive(Debug, Clone)]
+struct LookupOptions {
+    mode: SearchMode,
+    distance: u32,
+    format: OutputFormat,
+    limit: Option<usize>,
+    /// Matched words to skip before the `--limit` window.
+    offset: usize,
+    /// Normalized by `normalize_pos`.
+    pos: Option<String>,
+    max_level: Option<u8>,
+    /// Drop fields without example sentences.
+    only_with_examples: bool,
+    /// `--sort`; `None` ranks fuzzy matches by distance and keeps the
+    /// search order otherwise.
+    sort: Option<SortOrder>,
+    /// Match against the lowercased key index.
+    ignore_case: bool,
+    /// Print only the number of matching fields.
+    count: bool,
+    /// Print only definitions: no header, notes or `--limit` summary.
+    quiet: bool,
+    /// Retry an exact fuzzy lookup that found nothing at distance 1.
+    fallback: bool,
+    /// NFKC-normalize queries, so full-width `ＡＰＰＬＥ` finds `APPLE`.
+    normalize: bool,
+    /// Prefix each field with its `eijiro_parser::field_id`.
+    show_ids: bool,
+    /// `--stem`: retry a missed exact lookup with `stem_candidates`.
+    stem: bool,
+    /// `--first`: print only the best-ranked headword.
+    first: bool,
+    /// `--follow`: also look up the `Field::see_also` targets, one level deep.
+    follow: bool,
+    /// `--speak`: the TTS command template run after printing a match.
+    speak: Option<String>,
+    print: PrintOptions,
+}
 
-        let word_entry = builder
-            .get_object::<Entry>("word_entry")
-            .expect("Failed to get handle of word_entry");
+impl LookupOptions {
+    /// Whether the output of consecutive words is split by a blank line.
+    /// `--count` and `--output jsonl` print one line per item instead.
+    fn separates_words(&self) -> bool {
+        !self.count && self.format != OutputFormat::JsonLines
+    }
 
-        let word_desc = builder
-            .get_object::<TextView>("word_desc")
-            .expect("Failed to get handle of word_desc");
+    fn keep_field(&self, field: &Field) -> bool {
+        let pos_ok = match &self.pos {
+            Some(pos) => field.ident.as_deref().map(normalize_pos).as_ref() == Some(pos),
+            None => true,
+        };
+        let level_ok = match (self.max_level, field.level) {
+            (Some(max), Some(level)) => level <= max,
+            _ => true,
+        };
+        pos_ok && level_ok && (!self.only_with_examples || !field.examples.is_empty())
+    }
+
+    fn query<'q>(&self, word: &'q str, distance: u32) -> Query<'q> {
+        Query {
+            word,
+            mode: self.mode,
+            distance,
+            ignore_case: self.ignore_case,
+            cap: match self.mode {
+                SearchMode::Contains | SearchMode::Regex | SearchMode::Body => self.contains_cap(),
+                _ => None,
+            },
+        }
+    }
 
+    /// How many headwords a `--contains` scan collects before it stops: one
+    /// past the requested page, so `lookup_word` can tell more exist.
+    /// `None` when every match has to be seen for counting, field filtering
+    /// or sorting.
+    fn contains_cap(&self) -> Option<usize> {
+        if self.count
+            || self.pos.is_some()
+            || self.max_level.is_some()
+            || self.only_with_examples
+            || self.sort.is_some()
         {
-            let dict = dict.clone();
-            word_entry.connect_key_release_event(move |word_entry, key_event| {
-                word_list_store.clear();
-                word_desc.get_buffer().unwrap().set_text(&"");
+            return None;
+        }
+        self.limit.map(|limit| self.offset + limit + 1)
+    }
 
-                let query = word_entry.get_buffer().get_text();
-                if query == "" {
-                    // nothing to do
-                    return Inhibit(false);
-                }
+    /// Whether `--output jsonl` can write each match as the search finds
+    /// it: the mode walks the key index in order and nothing has to see
+    /// every match first to rank, sort, count or follow them.
+    fn streams(&self) -> bool {
+        self.format == OutputFormat::JsonLines
+            && !self.count
+            && self.sort.is_none()
+            && !self.first
+            && !self.follow
+            && matches!(
+                self.mode,
+                SearchMode::Prefix
+                    | SearchMode::Glob
+                    | SearchMode::Contains
+                    | SearchMode::Regex
+                    | SearchMode::Reverse
+            )
+    }
+}
 
-                let matcher = fst::automaton::Levenshtein::new(&query, 1).unwrap();
-                let mut stream = dict.keys.search(&matcher).into_stream();
+/// `{動}`, `動` and ` 動 ` all compare equal; ASCII tags ignore case.
+fn normalize_pos(tag: &str) -> String {
+    tag.trim()
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .trim()
+        .to_lowercase()
+}
 
-                let mut word_descs = vec![];
-                while let Some((k, idx)) = stream.next() {
-                    let item = std::str::from_utf8(k).unwrap();
-                    let mut desc = "".to_string();
-                    for f in &dict.fields[idx as usize] {
-                        desc += &printer(item, f);
-                        desc += "\n";
-                    }
-                    word_descs.push((String::from(item), desc));
-                }
+/// Folds full-width Latin letters, digits and symbols (and other
+/// compatibility forms) into the characters the headwords are written in.
+fn normalize_query(query: &str) -> String {
+    query.nfkc().collect()
+}
 
-                let mut prefix_ok = vec![];
-                let mut prefix_ng = vec![];
+/// Cost of each character edit between the query and a headword.
+const RANK_DISTANCE_WEIGHT: usize = 4;
+/// Cost of each query character past the prefix a headword shares with it,
+/// so `good` ranks `goof` above `gold` above `wood`, and an inflection
+/// like `goods` (which extends the query) pays nothing.
+const RANK_PREFIX_WEIGHT: usize = 2;
+/// Cost of each character of length difference, breaking ties between
+/// candidates at the same distance in favour of similar lengths.
+const RANK_LENGTH_WEIGHT: usize = 1;
 
-                for (word, desc) in word_descs.iter() {
-                    let tp = (word.clone(), desc.clone());
-                    if word.starts_with(&query) {
-                        prefix_ok.push(tp);
-                    } else {
-                        prefix_ng.push(tp);
-                    }
-                }
+/// How far `word` is from `query` for `rank_matches`; 0 is an exact match.
+/// Everything is counted in `char`s.
+fn match_score(query: &str, word: &str) -> usize {
+    let distance = eijiro_parser::levenshtein_chars(query, word);
+    let query_len = query.chars().count();
+    let shared = query
+        .chars()
+        .zip(word.chars())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let length_diff = (query_len as isize - word.chars().count() as isize).unsigned_abs();
+    distance * RANK_DISTANCE_WEIGHT
+        + (query_len - shared) * RANK_PREFIX_WEIGHT
+        + length_diff * RANK_LENGTH_WEIGHT
+}
 
-                prefix_ok.append(&mut prefix_ng);
+/// Orders matches by `match_score`, best first, for both frontends. Equal
+/// scores keep the incoming (alphabetical FST) order.
+fn rank_matches<T>(query: &str, mut results: Vec<(String, T)>) -> Vec<(String, T)> {
+    results.sort_by_cached_key(|(word, _)| match_score(query, word));
+    results
+}
 
-                let mut words = vec![];
-                let mut descs = vec![];
-                for (word, desc) in prefix_ok {
-                    append_word(&word, &word_list_store, word_column_id);
-                    words.push(word);
-                    descs.push(desc);
-                }
+/// `--sort`: the order matches are printed in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortOrder {
+    /// By headword, as the FST yields them.
+    Alpha,
+    /// Shortest headword first, so base forms come before derived ones.
+    Length,
+    /// See `rank_matches`.
+    Distance,
+    /// By `Field::rank`, see `sort_by_rank`.
+    Rank,
+}
+
+impl SortOrder {
+    fn from_arg(arg: &str) -> SortOrder {
+        match arg {
+            "length" => SortOrder::Length,
+            "distance" => SortOrder::Distance,
+            "rank" => SortOrder::Rank,
+            _ => SortOrder::Alpha,
+        }
+    }
+}
+
+/// Reorders matches for `order`. The sorts are stable, so equal keys keep
+/// the alphabetical order they arrived in. `Rank` needs the fields and is
+/// left to `sort_by_rank`.
+fn sort_results<T>(
+    order: SortOrder,
+    query: &str,
+    mut results: Vec<(String, T)>,
+) -> Vec<(String, T)> {
+    match order {
+        SortOrder::Alpha => results.sort_by(|(a, _), (b, _)| a.cmp(b)),
+        SortOrder::Length => results.sort_by_key(|(word, _)| word.chars().count()),
+        SortOrder::Distance => return rank_matches(query, results),
+        SortOrder::Rank => {}
+    }
+    results
+}
+
+/// `--sort rank`: orders each headword's fields by `Field::rank`, most
+/// common first, then the headwords by their best field. Unranked fields
+/// and headwords go last, in the order they arrived in.
+fn sort_by_rank(mut results: Matches) -> Matches {
+    let key = |f: &Cow<Field>| f.rank.map_or((1, 0), |rank| (0, rank));
+    for (_, fields) in &mut results {
+        fields.sort_by_key(key);
+    }
+    results.sort_by_key(|(_, fields)| fields.first().map_or((1, 0), key));
+    results
+}
+
+/// Up to `max` headwords near `word`, closest first. Tries distance 1, then
+/// 2, and stops at the first distance that finds anything.
+fn suggestions(dict: &Dict, word: &str, max: usize) -> Vec<String> {
+    for distance in 1..=2 {
+        let candidates = eijiro_parser::search(dict, word, distance).unwrap_or_default();
+        if !candidates.is_empty() {
+            return rank_matches(word, candidates)
+                .into_iter()
+                .map(|(headword, _)| headword)
+                .filter(|headword| headword != word)
+                .take(max)
+                .collect();
+        }
+    }
+    Vec::new()
+}
 
-                if descs.len() > 0 {
-                    word_desc.get_buffer().unwrap().set_text(&descs[0]);
+const default_lookup_distance: u32 = 0;
+
+/// The search part of a lookup, before any field filtering. `serve` runs
+/// it on behalf of `--remote` clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Query<'q> {
+    word: &'q str,
+    mode: SearchMode,
+    distance: u32,
+    ignore_case: bool,
+    /// See `LookupOptions::contains_cap`.
+    cap: Option<usize>,
+}
+
+impl<'q> Query<'q> {
+    fn search<'a>(&self, dict: &'a Dict) -> Result<Vec<(String, &'a [Field])>> {
+        let (word, distance) = (self.word, self.distance);
+        match self.mode {
+            // An exact lookup needs no automaton.
+            SearchMode::Fuzzy if distance == 0 && !self.ignore_case => Ok(dict
+                .get(word)
+                .map(|fields| vec![(word.to_string(), fields)])
+                .unwrap_or_default()),
+            SearchMode::Fuzzy => if self.ignore_case {
+                eijiro_parser::search_ignore_case(dict, word, distance)
+            } else {
+                eijiro_parser::search(dict, word, distance)
+            }
+            .map_err(|e| anyhow!("distance {}: {} (try a smaller --distance)", distance, e)),
+            SearchMode::Prefix if self.ignore_case => {
+                eijiro_parser::prefix_search_ignore_case(dict, word)
+            }
+            SearchMode::Prefix => eijiro_parser::prefix_search(dict, word),
+            SearchMode::Glob if self.ignore_case => {
+                eijiro_parser::glob_search_ignore_case(dict, word)
+            }
+            SearchMode::Glob => eijiro_parser::glob_search(dict, word),
+            SearchMode::Contains if self.ignore_case => {
+                eijiro_parser::contains_search_ignore_case(dict, word, self.cap)
+            }
+            SearchMode::Contains => eijiro_parser::contains_search(dict, word, self.cap),
+            SearchMode::Phrase => Ok(eijiro_parser::phrase_search(dict, word)),
+            SearchMode::Regex if self.ignore_case => {
+                eijiro_parser::regex_search_ignore_case(dict, word, self.cap)
+            }
+            SearchMode::Regex => eijiro_parser::regex_search(dict, word, self.cap),
+            SearchMode::Body if self.ignore_case => {
+                Ok(eijiro_parser::body_search_ignore_case(dict, word, self.cap))
+            }
+            SearchMode::Body => Ok(eijiro_parser::body_search(dict, word, self.cap)),
+            SearchMode::Reverse => Ok(eijiro_parser::reverse_search(dict, word)
+                .into_iter()
+                .map(|(item, f)| (item.to_string(), std::slice::from_ref(f)))
+                .collect()),
+        }
+    }
+
+    /// URL query parameters for `GET /lookup`.
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![
+            ("word", self.word.to_string()),
+            ("distance", self.distance.to_string()),
+            ("mode", self.mode.name().to_string()),
+        ];
+        if self.ignore_case {
+            params.push(("ignore_case", "1".to_string()));
+        }
+        if let Some(cap) = self.cap {
+            params.push(("cap", cap.to_string()));
+        }
+        params
+    }
+
+    fn from_params(params: &'q HashMap<String, String>) -> Result<Query<'q>, String> {
+        fn number<T: std::str::FromStr>(
+            params: &HashMap<String, String>,
+            name: &str,
+        ) -> Result<Option<T>, String> {
+            params
+                .get(name)
+                .map(|v| {
+                    v.parse::<T>()
+                        .map_err(|_| format!("{} must be a non-negative integer: {}", name, v))
+                })
+                .transpose()
+        }
+        Ok(Query {
+            word: params.get("word").ok_or("missing parameter: word")?,
+            mode: match params.get("mode") {
+                Some(name) => {
+                    SearchMode::from_name(name).ok_or_else(|| format!("unknown mode: {}", name))?
                 }
+                None => SearchMode::Fuzzy,
+            },
+            // Parsed as `u32` so an out-of-range distance is rejected, not
+            // truncated.
+            distance: number(params, "distance")?.unwrap_or(0),
+            ignore_case: params.get("ignore_case").is_some_and(|v| v == "1"),
+            cap: number(params, "cap")?,
+        })
+    }
+}
 
-                let descs = descs.clone();
-                let word_desc = word_desc.clone();
-                word_list.connect_row_activated(move |_, tp, _| {
-                    let empty = "".to_string();
-                    let text = match tp.get_indices().iter().nth(0) {
-                        Some(idx) => &descs[*idx as usize],
-                        None => &empty,
-                    };
+/// Matches per headword: borrowed from a local `Dict`, owned when they came
+/// from a `--remote` server.
+type Matches<'a> = Vec<(String, Vec<Cow<'a, Field>>)>;
 
-                    word_desc.get_buffer().unwrap().set_text(text);
-                });
+/// Drops the fields rejected by `LookupOptions::keep_field`, along with
+/// headwords left without any.
+fn keep_fields<'a, I>(results: I, opts: &LookupOptions) -> Matches<'a>
+where
+    I: IntoIterator<Item = (String, Vec<Cow<'a, Field>>)>,
+{
+    results
+        .into_iter()
+        .map(|(item, mut fields)| {
+            fields.retain(|f| opts.keep_field(f));
+            (item, fields)
+        })
+        .filter(|(_, fields)| !fields.is_empty())
+        .collect()
+}
 
-                Inhibit(false)
-            });
+/// One of several `--dict` caches, searched in the order given.
+struct Layer {
+    /// Shown next to the matches from this dictionary.
+    label: String,
+    dict: Dict,
+}
+
+impl Layer {
+    /// Labels `dict` with the last component of `path`, e.g. `personal`.
+    fn new(path: &Path, dict: Dict) -> Layer {
+        let label = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+        Layer { label, dict }
+    }
+}
+
+/// Where `lookup_word` gets its matches from.
+#[derive(Clone, Copy)]
+enum Backend<'a> {
+    Local(&'a Dict),
+    /// Several `--dict` paths. A headword found in more than one of them
+    /// appears once per dictionary.
+    Layered(&'a [Layer]),
+    Remote(&'a Remote),
+}
+
+impl<'a> Backend<'a> {
+    /// The dictionary for suggestions and completion: the first one of a
+    /// `Layered` backend.
+    fn local(&self) -> Option<&'a Dict> {
+        match self {
+            Backend::Local(dict) => Some(dict),
+            Backend::Layered(layers) => layers.first().map(|layer| &layer.dict),
+            Backend::Remote(_) => None,
         }
+    }
 
-        window.show_all();
-    });
+    /// The local dictionary `field`, as returned by `find` for `headword`,
+    /// was borrowed from, with its label when several are searched.
+    fn origin(&self, headword: &str, field: &Field) -> Option<(&'a Dict, Option<&'a str>)> {
+        match self {
+            Backend::Local(dict) => Some((dict, None)),
+            Backend::Layered(layers) => layers
+                .iter()
+                .find(|layer| {
+                    let fields = layer.dict.get(headword).unwrap_or_default();
+                    fields.as_ptr_range().contains(&(field as *const Field))
+                })
+                .map(|layer| (&layer.dict, Some(layer.label.as_str()))),
+            Backend::Remote(_) => None,
+        }
+    }
 
-    app.run(&[]);
-    gtk::main();
+    /// Runs the search selected by `opts.mode` and applies `keep_fields`.
+    fn find(&self, word: &str, opts: &LookupOptions, distance: u32) -> Result<Matches<'a>> {
+        let query = opts.query(word, distance);
+        let results = match self {
+            Backend::Local(dict) => keep_fields(
+                query.search(dict)?.into_iter().map(|(item, fields)| {
+                    (item, fields.iter().map(Cow::Borrowed).collect::<Vec<_>>())
+                }),
+                opts,
+            ),
+            Backend::Layered(layers) => {
+                let mut results = Vec::new();
+                for layer in layers.iter() {
+                    results.extend(Backend::Local(&layer.dict).find(word, opts, distance)?);
+                }
+                results
+            }
+            Backend::Remote(remote) => keep_fields(
+                remote
+                    .search(&query)?
+                    .into_iter()
+                    .map(|(item, fields)| (item, fields.into_iter().map(Cow::Owned).collect())),
+                opts,
+            ),
+        };
+        Ok(results)
+    }
 }
 
-fn main() {
-    pretty_env_logger::init();
-    let app = App::new("eijiro-rs")
-        .version("0.1.1 Forked")
-        .author("algon-320 <algon.0320@mail.com>")
-        .author("Akihiro Shoji <alpha.kai.net@alpha-kai-net.info>")
-        .about("English-Japanese dictionary (using Eijiro)")
-        .arg(Arg::with_name("word").required(false))
-        .arg(
-            Arg::with_name("gui_flag")
-                .help("gui frontend frag")
-                .short("g")
-                .long("gui")
-                .required(false),
-        );
-    let matches = app.get_matches();
+/// One headword in a `GET /lookup` response.
+#[derive(Serialize, Deserialize)]
+struct RemoteEntry<'a> {
+    word: Cow<'a, str>,
+    fields: Cow<'a, [Field]>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RemoteError {
+    error: String,
+}
+
+/// A `serve` instance queried by `--remote`.
+struct Remote {
+    base: String,
+    agent: ureq::Agent,
+}
+
+impl Remote {
+    fn new(base: &str) -> Remote {
+        Remote {
+            base: base.trim_end_matches('/').to_string(),
+            agent: ureq::Agent::new(),
+        }
+    }
 
-    let dict = match std::fs::read("./dict_dump.bincode") {
-        Ok(bytes) => {
-            info!("Loading dict");
-            let dict = bincode::deserialize(&bytes).unwrap();
-            info!("Loaded dict");
-            dict
+    fn search(&self, query: &Query) -> Result<Vec<(String, Vec<Field>)>> {
+        let mut request = self.agent.get(&format!("{}/lookup", self.base));
+        for (name, value) in query.params() {
+            request = request.query(name, &value);
         }
-        Err(_) => {
-            info!("Parse EIJIRO.txt");
-            let dict_str = std::fs::read_to_string("./EIJIRO.txt").unwrap();
-            let dict = eijiro_parser::parse(dict_str.as_str()).unwrap();
-            let _ = std::fs::write("./dict_dump.bincode", bincode::serialize(&dict).unwrap());
-            dict
+        match request.call() {
+            Ok(response) => {
+                let entries: Vec<RemoteEntry> = response
+                    .into_json()
+                    .with_context(|| format!("invalid response from {}", self.base))?;
+                Ok(entries
+                    .into_iter()
+                    .map(|e| (e.word.into_owned(), e.fields.into_owned()))
+                    .collect())
+            }
+            Err(ureq::Error::Status(status, response)) => {
+                let reason = response
+                    .into_json::<RemoteError>()
+                    .map(|e| e.error)
+                    .unwrap_or_default();
+                Err(anyhow!("{} returned {}: {}", self.base, status, reason))
+            }
+            Err(e) => Err(anyhow!("cannot reach {}: {}", self.base, e)),
         }
-    };
+    }
+}
+
+/// Answers `GET /lookup?word=X&distance=N` (plus `mode`, `ignore_case` and
+/// `cap`, see `Query::params`) with the matches as JSON.
+fn respond(dict: &Dict, url: &str) -> Result<String, (u16, String)> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    if path != "/lookup" {
+        return Err((404, format!("no such endpoint: {}", path)));
+    }
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let query = Query::from_params(&params).map_err(|e| (400, e))?;
+    let results = query.search(dict).map_err(|e| (400, e.to_string()))?;
+    let entries = results
+        .iter()
+        .map(|(word, fields)| RemoteEntry {
+            word: Cow::Borrowed(word),
+            fields: Cow::Borrowed(fields),
+        })
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_string(&entries).unwrap())
+}
+
+/// The `serve` subcommand: answers lookups over HTTP from one loaded `Dict`,
+/// on a thread per core.
+fn serve(dict: &Dict, matches: &ArgMatches) -> Result<()> {
+    let addr = matches.value_of("addr").unwrap();
+    let server =
+        tiny_http::Server::http(addr).map_err(|e| anyhow!("cannot listen on {}: {}", addr, e))?;
+    info!("Listening on http://{}", addr);
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    let (status, body) = match respond(dict, request.url()) {
+                        Ok(body) => (200, body),
+                        Err((status, error)) => (
+                            status,
+                            serde_json::to_string(&RemoteError { error }).unwrap(),
+                        ),
+                    };
+                    let response = tiny_http::Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(
+                            tiny_http::Header::from_bytes("Content-Type", "application/json")
+                                .unwrap(),
+                        );
+                    if let Err(e) = request.respond(response) {
+                        warn!("Failed to send a response: {}", e);
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
 
-    if matches.is_present("gui_flag") {
-        gui_frontend(dict);
+/// Prints the matches for `word` and returns how many fields matched,
+/// ignoring `--limit`.
+fn lookup_word(word: &str, backend: Backend, opts: &LookupOptions, out: &mut dyn Write) -> usize {
+    let normalized;
+    let word = if opts.normalize {
+        normalized = normalize_query(word);
+        normalized.as_str()
     } else {
-        cli_frontend(matches, dict);
+        word
+    };
+    if opts.streams() && backend.local().is_some() {
+        return stream_json_lines(word, backend, opts, out);
+    }
+    // The header would break JSON output, e.g. a file written with `--out`.
+    if !opts.count && !opts.quiet && opts.format == OutputFormat::Text {
+        outln!(
+            out,
+            "<Search word: [{}]>",
+            paint(word.to_string(), opts.print.color, |s| s.bold().to_string())
+        );
+    }
+    let mut results = match timed_find(backend, word, opts, opts.distance) {
+        Ok(results) => results,
+        Err(e) => {
+            error!("Cannot search [{}]: {}", word, e);
+            return 0;
+        }
+    };
+    // `--count` answers whether the word itself exists, so it never retries.
+    let retry = !opts.count && opts.mode == SearchMode::Fuzzy && opts.distance == 0;
+    if results.is_empty() && retry && opts.stem {
+        for base in eijiro_parser::stem_candidates(word) {
+            results = timed_find(backend, &base, opts, 0).unwrap_or_default();
+            if !results.is_empty() {
+                if !opts.quiet {
+                    let note = format!("No exact match; showing the base form [{}]:", base);
+                    match opts.format {
+                        OutputFormat::Text => outln!(out, "{}", note),
+                        OutputFormat::Json | OutputFormat::JsonLines => eprintln!("{}", note),
+                    }
+                }
+                break;
+            }
+        }
+    }
+    if results.is_empty() && retry && opts.fallback {
+        results = timed_find(backend, word, opts, 1).unwrap_or_default();
+        if !results.is_empty() && !opts.quiet {
+            let note = "No exact match; showing近い候補:";
+            match opts.format {
+                OutputFormat::Text => outln!(out, "{}", note),
+                // Keep stdout valid JSON.
+                OutputFormat::Json | OutputFormat::JsonLines => eprintln!("{}", note),
+            }
+        }
+    }
+    let matched = results.iter().map(|(_, fields)| fields.len()).sum();
+    if opts.count {
+        outln!(out, "{}", matched);
+        return matched;
+    }
+    if matched == 0 {
+        report_no_matches(word, backend, opts);
+    }
+    let query = if opts.ignore_case {
+        word.to_lowercase()
+    } else {
+        word.to_string()
+    };
+    let mut results = match (opts.sort, opts.mode) {
+        (Some(SortOrder::Rank), _) => sort_by_rank(results),
+        (Some(order), _) => sort_results(order, &query, results),
+        (None, SearchMode::Fuzzy) => rank_matches(&query, results),
+        // The best prefix or glob match is the closest one, not the first key.
+        (None, mode) if opts.first && mode != SearchMode::Reverse => rank_matches(&query, results),
+        (None, _) => results,
+    };
+    if opts.first {
+        results.truncate(1);
+    }
+    let total = results.len();
+    // A capped `--contains`, `--regex` or `--in-body` scan only knows a lower bound.
+    let capped = matches!(
+        opts.mode,
+        SearchMode::Contains | SearchMode::Regex | SearchMode::Body
+    ) && opts.contains_cap() == Some(total);
+    let start = opts.offset.min(total);
+    results.drain(..start);
+    if let Some(limit) = opts.limit {
+        results.truncate(limit);
+    }
+    let paged = start > 0 || results.len() < total;
+    let results_len = results.len();
+    let spoken = results.first().map(|(item, _)| item.clone());
+    let references = if opts.follow {
+        see_also_targets(&results)
+    } else {
+        Vec::new()
+    };
+    match opts.format {
+        OutputFormat::Text => {
+            // Several headwords (run, runs, running) each get a heading and
+            // are separated by a blank line.
+            let grouped = results.len() > 1;
+            for (i, (item, fields)) in results.into_iter().enumerate() {
+                if grouped && i > 0 {
+                    outln!(out);
+                }
+                let label = fields.first().and_then(|f| label_of(backend, &item, f));
+                // The query is not part of the headword in these modes.
+                let heading = if matches!(opts.mode, SearchMode::Reverse | SearchMode::Body) {
+                    Some(paint(item.clone(), opts.print.color, |s| {
+                        s.bold().to_string()
+                    }))
+                } else if grouped || item != word || label.is_some() {
+                    // Show which headword (and dictionary) a match landed on.
+                    Some(highlight(&item, word, opts.print.color))
+                } else {
+                    None
+                };
+                match (heading, label) {
+                    (Some(heading), Some(label)) => outln!(
+                        out,
+                        "{} {}",
+                        heading,
+                        paint(format!("[{}]", label), opts.print.color, |s| {
+                            s.dimmed().to_string()
+                        })
+                    ),
+                    (Some(heading), None) => outln!(out, "{}", heading),
+                    _ => {}
+                }
+                for f in &fields {
+                    let id = permalink(backend, &item, f).filter(|_| opts.show_ids);
+                    outln!(
+                        out,
+                        "{}",
+                        with_id_prefix(id, printer(&item, f, &opts.print), opts)
+                    );
+                }
+            }
+            if paged && !opts.quiet {
+                let total = if capped {
+                    format!("more than {}", total - 1)
+                } else {
+                    total.to_string()
+                };
+                if results_len == 0 {
+                    outln!(out, "... no matches past offset {} of {}", start, total);
+                } else {
+                    outln!(
+                        out,
+                        "... showing {}–{} of {} matches",
+                        start + 1,
+                        start + results_len,
+                        total
+                    );
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let entries = results
+                .iter()
+                .flat_map(|(item, fields)| {
+                    fields.iter().map(move |f| {
+                        JsonEntry::new(item, f)
+                            .with_raw(f, opts.print.raw)
+                            .with_id(permalink(backend, item, f).filter(|_| opts.show_ids))
+                            .with_dict(label_of(backend, item, f))
+                    })
+                })
+                .collect::<Vec<_>>();
+            outln!(out, "{}", serde_json::to_string_pretty(&entries).unwrap());
+        }
+        OutputFormat::JsonLines => {
+            for (item, fields) in &results {
+                for f in fields {
+                    let entry = JsonEntry::new(item, f)
+                        .with_raw(f, opts.print.raw)
+                        .with_id(permalink(backend, item, f).filter(|_| opts.show_ids))
+                        .with_dict(label_of(backend, item, f));
+                    print_json_line(out, &entry);
+                }
+            }
+        }
+    }
+    if let (Some(template), Some(headword)) = (&opts.speak, spoken) {
+        speak(template, &headword);
+    }
+    if !references.is_empty() {
+        // Exact lookups, and no further references.
+        let followed = LookupOptions {
+            mode: SearchMode::Fuzzy,
+            distance: 0,
+            limit: None,
+            offset: 0,
+            sort: None,
+            fallback: false,
+            stem: false,
+            first: false,
+            follow: false,
+            speak: None,
+            ..opts.clone()
+        };
+        for target in &references {
+            if followed.separates_words() {
+                outln!(out);
+            }
+            lookup_word(target, backend, &followed, out);
+        }
+    }
+    matched
+}
+
+fn report_no_matches(word: &str, backend: Backend, opts: &LookupOptions) {
+    eprintln!("No matches for '{}'", word);
+    if let (Some(dict), true) = (backend.local(), opts.mode != SearchMode::Reverse) {
+        let near = suggestions(dict, word, 5);
+        if !near.is_empty() {
+            eprintln!("Did you mean: {}?", near.join(", "));
+        }
+    }
+}
+
+/// `lookup_word` when `LookupOptions::streams`: each entry is written (and
+/// flushed) as the search yields it, with the field filters and the
+/// `--offset`/`--limit` window applied on the way. The scan stops at the
+/// end of the window, so the count returned only covers matches up to it.
+fn stream_json_lines(
+    word: &str,
+    backend: Backend,
+    opts: &LookupOptions,
+    out: &mut dyn Write,
+) -> usize {
+    let dicts = match backend {
+        Backend::Local(dict) => vec![dict],
+        Backend::Layered(layers) => layers.iter().map(|layer| &layer.dict).collect(),
+        Backend::Remote(_) => Vec::new(),
+    };
+    let end = opts.limit.map(|limit| opts.offset.saturating_add(limit));
+    let (mut seen, mut matched) = (0, 0);
+    let mut spoken = None;
+    let mut visit = |item: String, fields: &[Field]| {
+        if end.is_some_and(|end| seen >= end) {
+            return false;
+        }
+        let kept = fields
+            .iter()
+            .filter(|f| opts.keep_field(f))
+            .collect::<Vec<_>>();
+        if kept.is_empty() {
+            return true;
+        }
+        seen += 1;
+        matched += kept.len();
+        if seen > opts.offset {
+            for f in kept {
+                let entry = JsonEntry::new(&item, f)
+                    .with_raw(f, opts.print.raw)
+                    .with_id(permalink(backend, &item, f).filter(|_| opts.show_ids))
+                    .with_dict(label_of(backend, &item, f));
+                print_json_line(out, &entry);
+            }
+            spoken.get_or_insert(item);
+        }
+        end.is_none_or(|end| seen < end)
+    };
+    for dict in dicts {
+        let pattern = match opts.mode {
+            SearchMode::Prefix => KeyPattern::Prefix(word),
+            SearchMode::Glob => KeyPattern::Glob(word),
+            SearchMode::Contains => KeyPattern::Contains(word),
+            SearchMode::Regex => KeyPattern::Regex(word),
+            SearchMode::Reverse => {
+                for (item, f) in eijiro_parser::reverse_search(dict, word) {
+                    if !visit(item.to_string(), std::slice::from_ref(f)) {
+                        break;
+                    }
+                }
+                continue;
+            }
+            mode => unreachable!("{} search does not stream", mode.name()),
+        };
+        let result = eijiro_parser::for_each_match(dict, pattern, opts.ignore_case, &mut visit);
+        if let Err(e) = result {
+            error!("Cannot search [{}]: {}", word, e);
+            return matched;
+        }
+    }
+    if matched == 0 {
+        report_no_matches(word, backend, opts);
+    }
+    if let (Some(template), Some(headword)) = (&opts.speak, spoken) {
+        speak(template, &headword);
+    }
+    matched
+}
+
+/// `Backend::find`, logging its latency and match count at debug level.
+/// The clock is only read when debug logging is on.
+fn timed_find<'a>(
+    backend: Backend<'a>,
+    word: &str,
+    opts: &LookupOptions,
+    distance: u32,
+) -> Result<Matches<'a>> {
+    let started = log::log_enabled!(log::Level::Debug).then(std::time::Instant::now);
+    let results = backend.find(word, opts, distance);
+    if let (Some(started), Ok(results)) = (started, &results) {
+        debug!(
+            "{} search for [{}] at distance {}: {} headwords, {} fields in {:?}",
+            opts.mode.name(),
+            word,
+            distance,
+            results.len(),
+            results
+                .iter()
+                .map(|(_, fields)| fields.len())
+                .sum::<usize>(),
+            started.elapsed()
+        );
+    }
+    results
+}
+
+/// The `see_also` headwords of the printed fields that were not printed
+/// themselves, without duplicates, in order of appearance.
+fn see_also_targets(results: &Matches) -> Vec<String> {
+    let mut targets: Vec<String> = Vec::new();
+    for (_, fields) in results {
+        for target in fields.iter().flat_map(|f| &f.see_also) {
+            let shown = results.iter().any(|(item, _)| item == target);
+            if !shown && !targets.contains(target) {
+                targets.push(target.clone());
+            }
+        }
+    }
+    targets
+}
+
+#[cfg(target_os = "macos")]
+const DEFAULT_SPEAK_COMMAND: &str = "say";
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_SPEAK_COMMAND: &str = "espeak";
+
+/// Splits a `speak_command` template into a program and its arguments.
+fn speak_args(template: &str, word: &str) -> Vec<String> {
+    let mut args = template
+        .split_whitespace()
+        .map(|arg| arg.replace("{word}", word))
+        .collect::<Vec<_>>();
+    if !args.is_empty() && !template.contains("{word}") {
+        args.push(word.to_string());
+    }
+    args
+}
+
+/// Reads `word` aloud with the `--speak` command. Failures only warn, so
+/// a missing TTS engine never breaks a lookup.
+fn speak(template: &str, word: &str) {
+    let args = speak_args(template, word);
+    let (program, args) = match args.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    match std::process::Command::new(program)
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .status()
+    {
+        Ok(status) if !status.success() => warn!("{} exited with {}", program, status),
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "TTS command not found: {} (set speak_command in the config)",
+                program
+            )
+        }
+        Err(e) => warn!("Failed to run {}: {}", program, e),
+    }
+}
+
+const DEFAULT_PAGER: &str = "less";
+
+/// Starts `$PAGER` (default `less`) to read the results of a one-shot
+/// lookup. As in git, `LESS` defaults to `FRX`, so output that fits on one
+/// screen is printed as is, and an empty `PAGER` or `cat` turns paging off.
+fn spawn_pager() -> Option<std::process::Child> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| DEFAULT_PAGER.to_string());
+    let args = pager.split_whitespace().collect::<Vec<_>>();
+    let (program, args) = args.split_first()?;
+    if *program == "cat" {
+        return None;
+    }
+    let mut command = std::process::Command::new(program);
+    command.args(args).stdin(std::process::Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+    match command.spawn() {
+        Ok(child) => Some(child),
+        Err(e) => {
+            warn!("Failed to start the pager {}: {}", program, e);
+            None
+        }
+    }
+}
+
+/// The `field_id` of `field`. Finding its position needs the whole entry,
+/// so only a local dictionary has IDs.
+fn permalink(backend: Backend, headword: &str, field: &Field) -> Option<String> {
+    let (dict, label) = backend.origin(headword, field)?;
+    let position = dict.field_position(headword, field)?;
+    let id = eijiro_parser::field_id(headword, position);
+    // With several `--dict`s the label says which one, e.g. `personal/run#1`.
+    Some(match label {
+        Some(label) => format!("{}/{}", label, id),
+        None => id,
+    })
+}
+
+/// The label of the `--dict` `field` came from, when several are searched.
+fn label_of<'a>(backend: Backend<'a>, headword: &str, field: &Field) -> Option<&'a str> {
+    backend.origin(headword, field)?.1
+}
+
+fn with_id_prefix(id: Option<String>, text: String, opts: &LookupOptions) -> String {
+    match id {
+        Some(id) => format!(
+            "{} {}",
+            paint(format!("[{}]", id), opts.print.color, |s| s
+                .dimmed()
+                .to_string()),
+            text
+        ),
+        None => text,
+    }
+}
+
+/// `--id`: prints the one field a permalink refers to. With several
+/// `--dict`s, `label/headword#N` picks a dictionary; a bare `headword#N` is
+/// tried against each in order.
+fn lookup_id(id: &str, backend: Backend, opts: &LookupOptions, out: &mut dyn Write) -> usize {
+    let found = match backend {
+        Backend::Local(dict) => dict.get_by_id(id),
+        Backend::Layered(layers) => layers
+            .iter()
+            .find_map(|layer| {
+                let rest = id.strip_prefix(layer.label.as_str())?.strip_prefix('/')?;
+                layer.dict.get_by_id(rest)
+            })
+            .or_else(|| layers.iter().find_map(|layer| layer.dict.get_by_id(id))),
+        Backend::Remote(_) => None,
+    };
+    let (headword, field) = match found {
+        Some(found) => found,
+        None => {
+            eprintln!("No field with ID '{}'", id);
+            return 0;
+        }
+    };
+    let label = label_of(backend, headword, field);
+    let shown_id = permalink(backend, headword, field).filter(|_| opts.show_ids);
+    match opts.format {
+        OutputFormat::Text => {
+            let heading = paint(headword.to_string(), opts.print.color, |s| {
+                s.bold().to_string()
+            });
+            match label {
+                Some(label) => outln!(
+                    out,
+                    "{} {}",
+                    heading,
+                    paint(format!("[{}]", label), opts.print.color, |s| {
+                        s.dimmed().to_string()
+                    })
+                ),
+                None => outln!(out, "{}", heading),
+            }
+            let text = printer(headword, field, &opts.print);
+            outln!(out, "{}", with_id_prefix(shown_id, text, opts));
+        }
+        OutputFormat::Json => {
+            let entry = JsonEntry::new(headword, field)
+                .with_raw(field, opts.print.raw)
+                .with_id(shown_id)
+                .with_dict(label);
+            outln!(out, "{}", serde_json::to_string_pretty(&[entry]).unwrap());
+        }
+        OutputFormat::JsonLines => print_json_line(
+            out,
+            &JsonEntry::new(headword, field)
+                .with_raw(field, opts.print.raw)
+                .with_id(shown_id)
+                .with_dict(label),
+        ),
+    }
+    1
+}
+
+/// Writes `value` as one line of `--output jsonl` and flushes it, so a
+/// consumer such as `jq` sees each entry as soon as it is printed.
+fn print_json_line<T: Serialize>(out: &mut dyn Write, value: &T) {
+    let _ = writeln!(out, "{}", serde_json::to_string(value).unwrap()).and_then(|_| out.flush());
+}
+
+fn output_format(matches: &ArgMatches) -> OutputFormat {
+    match matches.value_of("output") {
+        Some("json") => OutputFormat::Json,
+        Some("jsonl") => OutputFormat::JsonLines,
+        _ => OutputFormat::Text,
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Stats {
+    headwords: usize,
+    fields: usize,
+    fields_with_examples: usize,
+    fields_with_complements: usize,
+    /// Fields by number of example sentences.
+    examples_per_field: ExampleCounts,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct ExampleCounts {
+    #[serde(rename = "0")]
+    none: usize,
+    #[serde(rename = "1-3")]
+    few: usize,
+    #[serde(rename = "4-10")]
+    some: usize,
+    #[serde(rename = "11+")]
+    many: usize,
+}
+
+impl ExampleCounts {
+    fn add(&mut self, examples: usize) {
+        match examples {
+            0 => self.none += 1,
+            1..=3 => self.few += 1,
+            4..=10 => self.some += 1,
+            _ => self.many += 1,
+        }
+    }
+
+    fn rows(&self) -> [(&'static str, usize); 4] {
+        [
+            ("0", self.none),
+            ("1-3", self.few),
+            ("4-10", self.some),
+            ("11+", self.many),
+        ]
+    }
+}
+
+impl Stats {
+    fn collect(dict: &Dict) -> Stats {
+        let mut stats = Stats {
+            headwords: dict.keys.len(),
+            ..Stats::default()
+        };
+        for field in dict.fields.iter().flatten() {
+            stats.fields += 1;
+            stats.examples_per_field.add(field.examples.len());
+            if !field.examples.is_empty() {
+                stats.fields_with_examples += 1;
+            }
+            if !field.explanation.complements.is_empty() {
+                stats.fields_with_complements += 1;
+            }
+        }
+        stats
+    }
+}
+
+fn print_stats(dict: &Dict, format: OutputFormat) {
+    let stats = Stats::collect(dict);
+    match format {
+        OutputFormat::Text => {
+            println!("headwords: {}", stats.headwords);
+            println!("fields: {}", stats.fields);
+            println!("fields with examples: {}", stats.fields_with_examples);
+            println!("fields with complements: {}", stats.fields_with_complements);
+            println!("examples per field:");
+            let rows = stats.examples_per_field.rows();
+            let width = rows
+                .iter()
+                .map(|(_, n)| n.to_string().len())
+                .max()
+                .unwrap_or(0);
+            for (label, n) in &rows {
+                println!("  {:<5} {:>width$}", label, n, width = width);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+        OutputFormat::JsonLines => print_json_line(&mut std::io::stdout(), &stats),
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Prints `Dict::memory_usage` right after loading. Fields of a mapped
+/// cache are decoded on lookup, so only the store size is known then.
+fn print_mem_report(dict: &Dict, format: OutputFormat) {
+    let usage = dict.memory_usage();
+    match format {
+        OutputFormat::Text => {
+            let backing = if usage.mapped { "mapped" } else { "in memory" };
+            println!("keys fst: {} ({})", human_size(usage.keys), backing);
+            println!(
+                "folded fst: {} ({})",
+                human_size(usage.folded_keys),
+                backing
+            );
+            println!("fields: {}", human_size(usage.fields));
+            if usage.fields_store > 0 {
+                println!(
+                    "fields store: {} ({})",
+                    human_size(usage.fields_store),
+                    backing
+                );
+            }
+            let total = usage.keys + usage.folded_keys + usage.fields + usage.fields_store;
+            println!("total: {}", human_size(total));
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&usage).unwrap()),
+        OutputFormat::JsonLines => print_json_line(&mut std::io::stdout(), &usage),
+    }
+}
+
+/// Escapes `\\`, tab and newlines so a value stays inside one TSV cell.
+fn escape_tsv(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Writes `headword<TAB>pos<TAB>body<TAB>examples` rows for every field.
+/// Complements and examples keep their source `◆`/`■` delimiters.
+fn export_tsv<W: Write>(dict: &Dict, out: &mut W) -> Result<()> {
+    for (headword, fields) in dict.entries() {
+        for f in fields {
+            let mut body = f.explanation.body.clone();
+            for c in &f.explanation.complements {
+                body += &format!("◆{}", c.body);
+            }
+            let mut examples = String::new();
+            for e in &f.examples {
+                examples += &format!("■{}", e.sentence);
+                for c in &e.complements {
+                    examples += &format!("◆{}", c.body);
+                }
+            }
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}",
+                escape_tsv(&headword),
+                escape_tsv(f.ident.as_deref().unwrap_or("")),
+                escape_tsv(&body),
+                escape_tsv(&examples)
+            )?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes every headword on its own line in key order. Only the key FST is
+/// streamed; `fields` is never read.
+fn export_words<W: Write>(dict: &Dict, out: &mut W) -> Result<()> {
+    let mut stream = (&dict.keys).into_stream();
+    while let Some((k, _)) = stream.next() {
+        out.write_all(k)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn export(dict: &Dict, matches: &ArgMatches) -> Result<()> {
+    let mut out: Box<dyn Write> = match matches.value_of("out") {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path).with_context(|| format!("failed to create {}", path))?,
+        )),
+        None => Box::new(std::io::BufWriter::new(std::io::stdout().lock())),
+    };
+    match matches.value_of("format") {
+        Some("words") => export_words(dict, &mut out),
+        _ => export_tsv(dict, &mut out),
+    }
+}
+
+/// Looks up each non-empty line of `input`, separating the results by a blank line.
+/// Returns the total number of matched fields over all lines.
+fn batch_lookup<R: BufRead>(
+    input: R,
+    backend: Backend,
+    opts: &LookupOptions,
+    out: &mut dyn Write,
+) -> usize {
+    let mut first = true;
+    let mut matched = 0;
+    for line in input.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to read stdin: {}", e);
+                break;
+            }
+        };
+        let word = line.trim();
+        if word.is_empty() {
+            continue;
+        }
+        if !first && opts.separates_words() {
+            outln!(out);
+        }
+        first = false;
+        matched += lookup_word(word, backend, opts, out);
+    }
+    matched
+}
+
+fn cli_frontend(matches: ArgMatches, config: &Config, backend: Backend) {
+    let opts = LookupOptions {
+        mode: if matches.is_present("reverse") {
+            SearchMode::Reverse
+        } else if matches.is_present("prefix") {
+            SearchMode::Prefix
+        } else if matches.is_present("glob") {
+            SearchMode::Glob
+        } else if matches.is_present("contains") {
+            SearchMode::Contains
+        } else if matches.is_present("phrase") {
+            SearchMode::Phrase
+        } else if matches.is_present("regex") {
+            SearchMode::Regex
+        } else if matches.is_present("in_body") {
+            SearchMode::Body
+        } else {
+            SearchMode::Fuzzy
+        },
+        distance: matches
+            .value_of("distance")
+            .map(|d| d.parse().unwrap())
+            .or(config.distance)
+            .unwrap_or(default_lookup_distance),
+        format: output_format(&matches),
+        limit: matches
+            .value_of("limit")
+            .map(|l| l.parse().unwrap())
+            .or(config.limit),
+        offset: matches.value_of("offset").map_or(0, |o| o.parse().unwrap()),
+        pos: matches.value_of("pos").map(normalize_pos),
+        max_level: matches.value_of("max_level").map(|l| l.parse().unwrap()),
+        only_with_examples: matches.is_present("only_with_examples"),
+        sort: matches.value_of("sort").map(SortOrder::from_arg),
+        ignore_case: matches.is_present("ignore_case"),
+        count: matches.is_present("count"),
+        quiet: matches.is_present("quiet"),
+        fallback: !matches.is_present("no_fallback"),
+        normalize: !matches.is_present("no_normalize"),
+        show_ids: matches.is_present("show_ids"),
+        stem: matches.is_present("stem"),
+        first: matches.is_present("first"),
+        follow: matches.is_present("follow"),
+        speak: if matches.is_present("speak") {
+            let template = config.speak_command.as_deref();
+            Some(template.unwrap_or(DEFAULT_SPEAK_COMMAND).to_string())
+        } else {
+            None
+        },
+        print: PrintOptions {
+            color: match matches
+                .value_of("color")
+                .map(ColorChoice::from_arg)
+                .or(config.color)
+            {
+                Some(ColorChoice::Always) => true,
+                Some(ColorChoice::Never) => false,
+                _ => !matches.is_present("out") && std::io::stdout().is_terminal(),
+            },
+            examples: !matches.is_present("no_examples"),
+            max_examples: matches.value_of("max_examples").map(|n| n.parse().unwrap()),
+            complements: !matches.is_present("no_complements"),
+            parts: matches
+                .value_of("fields")
+                .map(|list| Part::parse_list(list).unwrap()),
+            raw: matches.is_present("raw"),
+        },
+    };
+    let words = matches
+        .values_of("word")
+        .map(|words| words.collect::<Vec<_>>())
+        .unwrap_or_default();
+    // Only the one-shot path is paged; the REPL and `--batch` read stdin.
+    let one_shot = matches.is_present("id")
+        || !(words.is_empty() || matches.is_present("batch") || words == ["-"]);
+    let mut pager = if one_shot
+        && !matches.is_present("out")
+        && !matches.is_present("no_pager")
+        && std::io::stdout().is_terminal()
+    {
+        spawn_pager()
+    } else {
+        None
+    };
+    let mut writer: Box<dyn Write> = match matches.value_of_os("out").map(Path::new) {
+        Some(path) => match std::fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))
+        {
+            Ok(file) => Box::new(std::io::BufWriter::new(file)),
+            Err(e) => {
+                error!("{:#}", e);
+                std::process::exit(1);
+            }
+        },
+        None => match pager.as_mut().and_then(|pager| pager.stdin.take()) {
+            Some(stdin) => Box::new(stdin),
+            None => Box::new(std::io::stdout()),
+        },
+    };
+    let out = writer.as_mut();
+    let matched = if let (Some(id), Some(_)) = (matches.value_of("id"), backend.local()) {
+        lookup_id(id, backend, &opts, out)
+    } else if matches.is_present("batch") || words == ["-"] {
+        batch_lookup(std::io::stdin().lock(), backend, &opts, out)
+    } else if words.is_empty() {
+        repl(backend, &opts, out);
+        return;
+    } else {
+        let mut matched = 0;
+        for (i, word) in words.iter().enumerate() {
+            if i > 0 && opts.separates_words() {
+                outln!(out);
+            }
+            matched += lookup_word(word, backend, &opts, out);
+        }
+        matched
+    };
+    match out.flush() {
+        // The pager was quit before reading everything.
+        Err(e) if pager.is_some() && e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            error!("Failed to write the results: {}", e);
+            std::process::exit(1);
+        }
+        Ok(()) => {}
+    }
+    if let Some(mut pager) = pager {
+        // Closing its stdin lets the pager see the end of the output.
+        drop(writer);
+        let _ = pager.wait();
+    }
+    // Like grep: success as long as at least one word matched.
+    if matched == 0 {
+        std::process::exit(1);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn from_arg(arg: &str) -> ColorChoice {
+        match arg {
+            "always" => ColorChoice::Always,
+            "never" => ColorChoice::Never,
+            _ => ColorChoice::Auto,
+        }
+    }
+}
+
+/// Defaults read from `config_path()`. Command-line flags take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    dict_path: Option<PathBuf>,
+    source_path: Option<PathBuf>,
+    distance: Option<u32>,
+    color: Option<ColorChoice>,
+    limit: Option<usize>,
+    /// `--speak` command; `{word}` is replaced by the headword, which is
+    /// appended when the template has no placeholder.
+    speak_command: Option<String>,
+}
+
+impl Config {
+    /// A missing file yields the default (empty) config.
+    fn load(path: &Path) -> Result<Config> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        toml::from_str(&text).with_context(|| format!("invalid config {}", path.display()))
+    }
+}
+
+/// Expands a leading `~` and `$VAR`/`${VAR}` in a `--dict`/`--source` path
+/// or config value. Unset variables and paths that are not valid UTF-8 are
+/// kept as written.
+fn expand_path(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(text) => PathBuf::from(expand_vars(text, |name| std::env::var(name).ok())),
+        None => path.to_path_buf(),
+    }
+}
+
+fn expand_vars(text: &str, var: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with('/') {
+            if let Some(home) = var("HOME") {
+                out.push_str(&home);
+                rest = after;
+            }
+        }
+    }
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, end) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(len) => (&braced[..len], len + 2),
+                None => ("", 0),
+            },
+            None => {
+                let len = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..len], len)
+            }
+        };
+        match var(name).filter(|_| !name.is_empty()) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&rest[start..start + 1 + end]),
+        }
+        rest = &after[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("eijiro").join("config.toml"))
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".eijiro_history"))
+}
+
+/// Queries committed in the GUI, one per line, most recent first.
+#[cfg(feature = "gui")]
+fn gui_history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".eijiro_gui_history"))
+}
+
+/// Number of queries the GUI history keeps.
+#[cfg(feature = "gui")]
+const GUI_HISTORY_LEN: usize = 50;
+
+/// Moves `query` to the front of `history`, dropping the oldest entries
+/// beyond `GUI_HISTORY_LEN`.
+#[cfg(feature = "gui")]
+fn push_history(history: &mut Vec<String>, query: &str) {
+    history.retain(|q| q != query);
+    history.insert(0, query.to_string());
+    history.truncate(GUI_HISTORY_LEN);
+}
+
+/// Headwords offered for Tab in the REPL; short prefixes match thousands.
+const COMPLETION_LIMIT: usize = 50;
+
+/// Completes the REPL line against the dictionary's headwords. The whole
+/// line is the prefix, so phrases like `look u` complete to `look up`.
+struct WordCompleter<'a> {
+    /// `None` for a `--remote` dictionary, which is not completed.
+    dict: Option<&'a Dict>,
+}
+
+impl Completer for WordCompleter<'_> {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        match self.dict {
+            Some(dict) if !prefix.is_empty() => Ok((
+                0,
+                eijiro_parser::prefix_keys(dict, prefix, COMPLETION_LIMIT),
+            )),
+            _ => Ok((0, Vec::new())),
+        }
+    }
+}
+
+impl Hinter for WordCompleter<'_> {
+    type Hint = String;
+}
+
+impl Highlighter for WordCompleter<'_> {}
+
+impl Validator for WordCompleter<'_> {}
+
+impl Helper for WordCompleter<'_> {}
+
+fn repl(backend: Backend, opts: &LookupOptions, out: &mut dyn Write) {
+    let mut editor = match Editor::<WordCompleter, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            error!("Failed to start line editor: {}", e);
+            return;
+        }
+    };
+    editor.set_helper(Some(WordCompleter {
+        dict: backend.local(),
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file just means this is the first session.
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let word = match editor.readline("=> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                error!("Failed to read line: {}", e);
+                break;
+            }
+        };
+        let word = word.trim_end();
+        // Like the GUI, an empty query does nothing; the prompt just reappears.
+        if word.trim().is_empty() {
+            continue;
+        }
+        if word == ":exit" {
+            break;
+        }
+        let _ = editor.add_history_entry(word);
+        lookup_word(word, backend, opts, out);
+        let _ = out.flush();
+    }
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            warn!("Failed to save history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Starts the GUI, searching for `initial` (e.g. `-g apple`) right away
+/// unless it is blank.
+#[cfg(feature = "gui")]
+fn gui_frontend(dict: Dict, initial: String) -> Result<()> {
+    use gio::prelude::*;
+    use glib::{Type, Value};
+    use gtk::prelude::*;
+    use gtk::{
+        Application, Builder, Button, CellRendererText, Clipboard, Entry, ListStore, SpinButton,
+        TextTag, TextView, TreeView, TreeViewColumn, Window,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    // Without a display (e.g. over SSH) this fails, where `app.run` would
+    // only log a warning and return.
+    gtk::init().map_err(|_| {
+        anyhow!(
+            "cannot start the GUI: no display available (is DISPLAY or WAYLAND_DISPLAY set?); \
+             run without -g/--gui to use the command line"
+        )
+    })?;
+    let app = Application::new(Some("info.alpha-kai-net.eijiro"), Default::default())
+        .map_err(|e| anyhow!("failed to initialize the GTK application: {}", e))?;
+    //let glade_file_path = "eijiro.glade";
+    let dict = Arc::new(dict);
+    app.connect_activate(move |app| {
+        let builder = Builder::from_string(include_str!("../eijiro.glade"));
+        let window = builder
+            .get_object::<Window>("window")
+            .expect("Failed to get handle of window");
+        window.connect_delete_event(|_, _| {
+            gtk::main_quit();
+            Inhibit(false)
+        });
+
+        fn append_word(word: &str, word_list_store: &ListStore, word_column_id: u32) {
+            let iter = word_list_store.insert(-1);
+            word_list_store.set_value(&iter, word_column_id, &word.to_value() as &Value);
+        }
+
+        // Single string column lists; returns the store and its column id.
+        fn setup_list(list: &TreeView, title: &str) -> (ListStore, u32) {
+            // TreeView Element Types
+            let column_types = [Type::String];
+            let store = ListStore::new(&column_types);
+            let column = TreeViewColumn::new();
+            let column_id: u32 = 0;
+            // Initialize & config column
+            {
+                column.set_title(title);
+                let cell_renderer = CellRendererText::new();
+                column.pack_start(&cell_renderer, true);
+                column.add_attribute(&cell_renderer, "text", column_id as i32);
+                list.append_column(&column);
+            }
+            list.set_model(Some(&store));
+
+            (store, column_id)
+        }
+
+        // Setup word_list
+        let word_list = builder
+            .get_object::<TreeView>("word_list")
+            .expect("Failed to get handle of word_list");
+        let (word_list_store, word_column_id) = setup_list(&word_list, "Word");
+        word_list.set_activate_on_single_click(true);
+
+        let word_entry = builder
+            .get_object::<Entry>("word_entry")
+            .expect("Failed to get handle of word_entry");
+
+        let word_desc = builder
+            .get_object::<TextView>("word_desc")
+            .expect("Failed to get handle of word_desc");
+
+        let copy_button = builder
+            .get_object::<Button>("copy_button")
+            .expect("Failed to get handle of copy_button");
+        {
+            let word_desc = word_desc.clone();
+            copy_button.connect_clicked(move |_| {
+                // Copy the whole definition, not just the selected part.
+                let buffer = word_desc.get_buffer().unwrap();
+                let (start, end) = buffer.get_bounds();
+                if let Some(text) = buffer.get_text(&start, &end, false) {
+                    Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(&text);
+                }
+            });
+        }
+
+        let distance_spin = builder
+            .get_object::<SpinButton>("distance_spin")
+            .expect("Failed to get handle of distance_spin");
+
+        // Descriptions of the rows currently in `word_list_store`, in row order.
+        // Each description starts with its headword; the span is the
+        // `match_span` of that headword, in characters.
+        type Desc = (String, Option<Range<usize>>);
+        let match_tag = TextTag::new(Some("match"));
+        match_tag.set_property_weight(700);
+        match_tag.set_property_background(Some("#fff2a8"));
+        word_desc
+            .get_buffer()
+            .unwrap()
+            .get_tag_table()
+            .unwrap()
+            .add(&match_tag);
+        fn show_desc(word_desc: &TextView, match_tag: &TextTag, (text, span): &Desc) {
+            let buffer = word_desc.get_buffer().unwrap();
+            buffer.set_text(text);
+            if let Some(span) = span {
+                let start = buffer.get_iter_at_offset(span.start as i32);
+                let end = buffer.get_iter_at_offset(span.end as i32);
+                buffer.apply_tag(match_tag, &start, &end);
+            }
+        }
+
+        // Descriptions of the rows currently in `word_list_store`, in row order.
+        let descs: Rc<RefCell<Vec<Desc>>> = Rc::new(RefCell::new(Vec::new()));
+        // Generation, ranked rows and, when there are none, suggestions.
+        type SearchResult = (u64, Vec<(String, Desc)>, Vec<String>);
+        {
+            let descs = descs.clone();
+            let word_desc = word_desc.clone();
+            let match_tag = match_tag.clone();
+            word_list.connect_row_activated(move |_, tp, _| {
+                let descs = descs.borrow();
+                match tp
+                    .get_indices()
+                    .first()
+                    .and_then(|idx| descs.get(*idx as usize))
+                {
+                    Some(desc) => show_desc(&word_desc, &match_tag, desc),
+                    None => word_desc.get_buffer().unwrap().set_text(""),
+                }
+            });
+        }
+
+        // Searches run on a worker thread and post their rows back here.
+        // Each request is tagged with a generation so results of a query
+        // that has since been retyped are dropped instead of shown.
+        let generation = Rc::new(Cell::new(0u64));
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        {
+            let generation = generation.clone();
+            let descs = descs.clone();
+            let word_list_store = word_list_store.clone();
+            let word_desc = word_desc.clone();
+            receiver.attach(None, move |(tag, rows, near): SearchResult| {
+                if tag != generation.get() {
+                    return glib::Continue(true);
+                }
+                if !near.is_empty() {
+                    let text = format!("Did you mean: {}?", near.join(", "));
+                    word_desc.get_buffer().unwrap().set_text(&text);
+                }
+                let mut descs = descs.borrow_mut();
+                for (word, desc) in rows {
+                    append_word(&word, &word_list_store, word_column_id);
+                    descs.push(desc);
+                }
+                if let Some(first) = descs.first() {
+                    show_desc(&word_desc, &match_tag, first);
+                }
+                glib::Continue(true)
+            });
+        }
+
+        // Re-runs the search for the current query and distance.
+        let refresh: Rc<dyn Fn()> = {
+            let dict = dict.clone();
+            let word_entry = word_entry.clone();
+            let distance_spin = distance_spin.clone();
+            Rc::new(move || {
+                word_list_store.clear();
+                word_desc.get_buffer().unwrap().set_text(&"");
+                descs.borrow_mut().clear();
+                generation.set(generation.get() + 1);
+
+                let query = normalize_query(&word_entry.get_buffer().get_text());
+                if query == "" {
+                    // nothing to do
+                    return;
+                }
+                let distance = distance_spin.get_value_as_int() as u32;
+
+                let (dict, sender, tag) = (dict.clone(), sender.clone(), generation.get());
+                std::thread::spawn(move || {
+                    let mut word_descs = vec![];
+                    for (item, fields) in
+                        eijiro_parser::search(&dict, &query, distance).unwrap_or_default()
+                    {
+                        let mut desc = format!("{}\n", item);
+                        for f in fields {
+                            desc += &printer(&item, f, &PrintOptions::plain());
+                            desc += "\n";
+                        }
+                        let span = match_span(&item, &query).map(|span| {
+                            item[..span.start].chars().count()..item[..span.end].chars().count()
+                        });
+                        word_descs.push((item, (desc, span)));
+                    }
+                    let near = if word_descs.is_empty() {
+                        suggestions(&dict, &query, 5)
+                    } else {
+                        Vec::new()
+                    };
+                    // The receiver is gone once the window has been closed.
+                    let _ = sender.send((tag, rank_matches(&query, word_descs), near));
+                });
+            })
+        };
+        {
+            let refresh = refresh.clone();
+            word_entry.connect_key_release_event(move |_, _| {
+                refresh();
+                Inhibit(false)
+            });
+        }
+        {
+            let refresh = refresh.clone();
+            distance_spin.connect_value_changed(move |_| refresh());
+        }
+        if !initial.trim().is_empty() {
+            word_entry.set_text(&initial);
+            refresh();
+        }
+
+        // Setup history_list
+        let history_list = builder
+            .get_object::<TreeView>("history_list")
+            .expect("Failed to get handle of history_list");
+        let (history_store, history_column_id) = setup_list(&history_list, "History");
+        history_list.set_activate_on_single_click(true);
+        let history = Rc::new(RefCell::new(
+            gui_history_path()
+                .and_then(|path| std::fs::read_to_string(path).ok())
+                .map(|text| text.lines().map(str::to_string).collect::<Vec<_>>())
+                .unwrap_or_default(),
+        ));
+        let show_history = {
+            let history_store = history_store.clone();
+            move |history: &[String]| {
+                history_store.clear();
+                for query in history.iter().take(GUI_HISTORY_LEN) {
+                    append_word(query, &history_store, history_column_id);
+                }
+            }
+        };
+        show_history(&history.borrow());
+        {
+            let history = history.clone();
+            word_entry.connect_activate(move |word_entry| {
+                let query = word_entry.get_buffer().get_text();
+                if query.trim().is_empty() {
+                    return;
+                }
+                let mut history = history.borrow_mut();
+                push_history(&mut history, query.trim());
+                show_history(&history);
+                if let Some(path) = gui_history_path() {
+                    if let Err(e) = std::fs::write(&path, history.join("\n")) {
+                        warn!("Failed to save history to {}: {}", path.display(), e);
+                    }
+                }
+            });
+        }
+        {
+            let word_entry = word_entry.clone();
+            history_list.connect_row_activated(move |_, tp, _| {
+                let history = history.borrow();
+                if let Some(query) = tp
+                    .get_indices()
+                    .first()
+                    .and_then(|idx| history.get(*idx as usize))
+                {
+                    word_entry.set_text(query);
+                    refresh();
+                }
+            });
+        }
+
+        window.show_all();
+    });
+
+    app.run(&[]);
+    gtk::main();
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SourceEncoding {
+    Utf8,
+    ShiftJis,
+    /// UTF-8 if the beginning of the file is valid UTF-8, Shift_JIS otherwise.
+    Auto,
+}
+
+/// Writes the cache at `dict_path`, as one gzipped file for a `.gz` path.
+/// Either way the old files are replaced by rename, never truncated, since
+/// another process (e.g. `serve`) may have them mapped.
+fn save_dict(dict: &Dict, dict_path: &Path) -> Result<()> {
+    if is_gzip(dict_path) {
+        write_gzip_dict(dict, dict_path)
+    } else {
+        dict.save(dict_path)
+    }
+}
+
+/// Loads a second or later `--dict`. Without its own `--source`, the cache
+/// must already exist; it is used as is.
+fn load_layer(
+    dict_path: &Path,
+    source_path: Option<&Path>,
+    encoding: SourceEncoding,
+    rebuild: bool,
+    parse: ParseOptions,
+    strict: bool,
+) -> Result<Dict> {
+    match source_path {
+        Some(source_path) => load_dict(dict_path, source_path, encoding, rebuild, parse, strict),
+        None => {
+            ensure!(
+                dict_path.exists(),
+                "no dictionary at {} and no --source to build it from",
+                dict_path.display()
+            );
+            // An empty source path has no stamp, so the cache is never stale.
+            load_dict(dict_path, Path::new(""), encoding, false, parse, strict)
+        }
+    }
+}
+
+/// The `validate` subcommand: parses `path` without keeping the fields and
+/// prints the counts and every line that failed. Returns whether all lines
+/// parsed.
+fn validate(path: &Path, encoding: SourceEncoding) -> Result<bool> {
+    let source = open_source(path, encoding)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let validation = eijiro_parser::validate_reader(source)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    println!("headwords: {}", validation.headwords);
+    println!("fields: {}", validation.fields);
+    println!("skipped lines: {}", validation.skipped.len());
+    for e in &validation.skipped {
+        println!("  {}", e);
+    }
+    Ok(validation.is_clean())
+}
+
+/// The `add` subcommand: parses another source and merges it into the cache.
+fn add_entries(
+    mut dict: Dict,
+    dict_path: &Path,
+    encoding: SourceEncoding,
+    matches: &ArgMatches,
+) -> Result<()> {
+    let path = &expand_path(Path::new(matches.value_of_os("source").unwrap()));
+    let source = open_source(path, encoding)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let extra = eijiro_parser::parse_reader(source)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let added = extra.keys.len();
+    dict.merge(extra)?;
+    save_dict(&dict, dict_path)
+        .with_context(|| format!("failed to write cache to {}", dict_path.display()))?;
+    info!("Merged {} headwords from {}", added, path.display());
+    Ok(())
+}
+
+/// Whether `path` names a gzip-compressed source or cache.
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Reads a cache written by `write_gzip_dict`.
+fn read_gzip_dict(path: &Path) -> Result<Dict> {
+    let file = std::fs::File::open(path)?;
+    Ok(bincode::deserialize_from(BufReader::new(GzDecoder::new(
+        BufReader::new(file),
+    )))?)
+}
+
+/// Writes `dict` as one gzipped bincode file, for a `--dict` ending in
+/// `.gz`. Unlike the `Dict::save` directory it cannot be memory-mapped and
+/// is read into memory as a whole.
+fn write_gzip_dict(dict: &Dict, path: &Path) -> Result<()> {
+    eijiro_parser::replace_file(path, &mut |out| {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        bincode::serialize_into(&mut encoder, dict)?;
+        encoder.finish()?;
+        Ok(())
+    })
+}
+
+fn open_source(path: &Path, encoding: SourceEncoding) -> std::io::Result<Box<dyn BufRead>> {
+    let file = std::fs::File::open(path)?;
+    let file: Box<dyn Read> = if is_gzip(path) {
+        Box::new(GzDecoder::new(BufReader::new(file)))
+    } else {
+        Box::new(file)
+    };
+    let mut reader = BufReader::with_capacity(1 << 16, file);
+    let sjis = match encoding {
+        SourceEncoding::Utf8 => false,
+        SourceEncoding::ShiftJis => true,
+        SourceEncoding::Auto => {
+            // A character cut off at the end of the buffer is not an error.
+            let head = reader.fill_buf()?;
+            matches!(std::str::from_utf8(head), Err(e) if e.error_len().is_some())
+        }
+    };
+    if sjis {
+        let decoder = DecodeReaderBytesBuilder::new()
+            .encoding(Some(encoding_rs::SHIFT_JIS))
+            .build(reader);
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// A cache is stale once its source was modified later or changed size.
+/// Caches that do not record a source are trusted.
+fn cache_is_stale(cached: Option<SourceStamp>, source: SourceStamp) -> bool {
+    cached.is_some_and(|cached| source.modified > cached.modified || source.len != cached.len)
+}
+
+/// Whether `dict` was parsed with `ParseOptions::keep_raw`.
+fn has_raw_lines(dict: &Dict) -> bool {
+    dict.entries()
+        .next()
+        .is_some_and(|(_, fields)| fields.iter().any(|f| f.raw.is_some()))
+}
+
+/// Skipped lines logged one by one with `warn!`; the rest only with `debug!`.
+const SKIPPED_LINES_SHOWN: usize = 10;
+
+fn report_skipped(source_path: &Path, skipped: &[ParseError]) {
+    for (i, e) in skipped.iter().enumerate() {
+        if i < SKIPPED_LINES_SHOWN {
+            warn!("{}: skipped {}", source_path.display(), e);
+        } else {
+            debug!("{}: skipped {}", source_path.display(), e);
+        }
+    }
+    if skipped.len() > SKIPPED_LINES_SHOWN {
+        warn!(
+            "{}: skipped {} lines in all (RUST_LOG=debug lists them; --strict stops at the first)",
+            source_path.display(),
+            skipped.len()
+        );
+    }
+}
+
+fn load_dict(
+    dict_path: &Path,
+    source_path: &Path,
+    encoding: SourceEncoding,
+    rebuild: bool,
+    parse: ParseOptions,
+    strict: bool,
+) -> Result<Dict> {
+    // Single-file dump written by older versions.
+    let legacy_path = dict_path.with_extension("bincode");
+    let mut cache_err = None;
+    let cached = if rebuild {
+        info!("Rebuilding dict");
+        None
+    } else if is_gzip(dict_path) && dict_path.is_file() {
+        info!("Loading dict");
+        match read_gzip_dict(dict_path) {
+            Ok(dict) => {
+                info!("Loaded dict");
+                Some(dict)
+            }
+            Err(e) => {
+                cache_err = Some(anyhow!(
+                    "failed to read cached dict at {}: {}",
+                    dict_path.display(),
+                    e
+                ));
+                None
+            }
+        }
+    } else if dict_path.is_dir() {
+        info!("Opening dict");
+        match Dict::load(dict_path) {
+            Ok(dict) => {
+                info!("Opened dict");
+                Some(dict)
+            }
+            Err(e) => {
+                cache_err = Some(anyhow!(
+                    "failed to open cached dict at {}: {}",
+                    dict_path.display(),
+                    e
+                ));
+                None
+            }
+        }
+    } else if let Ok(bytes) = std::fs::read(&legacy_path) {
+        info!("Loading dict");
+        match bincode::deserialize::<Dict>(&bytes) {
+            Ok(dict) => {
+                info!("Loaded dict");
+                Some(dict)
+            }
+            Err(e) => {
+                cache_err = Some(anyhow!(
+                    "failed to parse cached dict at {}: {}",
+                    legacy_path.display(),
+                    e
+                ));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(dict) = cached {
+        // Without a readable source the cache is all there is.
+        match SourceStamp::of(source_path) {
+            Ok(stamp) if cache_is_stale(dict.source, stamp) => info!(
+                "{} changed since the cache was built; rebuilding",
+                source_path.display()
+            ),
+            Ok(_) if parse.keep_raw && !has_raw_lines(&dict) => {
+                info!("The cache has no raw source lines; rebuilding")
+            }
+            _ => return Ok(dict),
+        }
+    }
+
+    let source = match open_source(source_path, encoding) {
+        Ok(source) => source,
+        Err(e) => {
+            let err = if e.kind() == std::io::ErrorKind::NotFound {
+                anyhow!("{} not found", source_path.display())
+            } else {
+                anyhow!("failed to read {}: {}", source_path.display(), e)
+            };
+            return Err(match cache_err {
+                Some(cache_err) => err.context(cache_err),
+                None => err,
+            });
+        }
+    };
+    if let Some(e) = cache_err {
+        warn!("{}; rebuilding from {}", e, source_path.display());
+    }
+
+    info!("Parse {}", source_path.display());
+    let context = || format!("failed to parse {}", source_path.display());
+    let mut dict = if strict {
+        eijiro_parser::parse_reader_with(source, parse).with_context(context)?
+    } else {
+        let (dict, skipped) =
+            eijiro_parser::parse_reader_with_skipped(source, parse).with_context(context)?;
+        report_skipped(source_path, &skipped);
+        dict
+    };
+    dict.source = SourceStamp::of(source_path).ok();
+    // A `serve` or GUI on the same cache keeps its own mapping of the old
+    // files; see `save_dict`.
+    if let Err(e) = save_dict(&dict, dict_path) {
+        warn!("failed to write cache to {}: {}", dict_path.display(), e);
+    }
+    Ok(dict)
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let mut app = App::new("eijiro-rs")
+        .version("0.1.1 Forked")
+        .author("algon-320 <algon.0320@mail.com>")
+        .author("Akihiro Shoji <alpha.kai.net@alpha-kai-net.info>")
+        .about("English-Japanese dictionary (using Eijiro)")
+        .arg(
+            Arg::with_name("word")
+                .help("words to look up (`-` reads one word per line from stdin)")
+                .multiple(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .help("read words to look up from stdin, one per line")
+                .long("batch")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("gui_flag")
+                .help("gui frontend frag")
+                .short("g")
+                .long("gui")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("distance")
+                .help("max Levenshtein distance for fuzzy lookup (default: 0)")
+                .short("d")
+                .long("distance")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| format!("distance must be a non-negative integer: {}", v))
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .help("max number of matched words to print")
+                .long("limit")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("limit must be a non-negative integer: {}", v))
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("offset")
+                .help("skip this many matched words before printing (for paging with --limit)")
+                .long("offset")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("offset must be a non-negative integer: {}", v))
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("output format (jsonl: one JSON object per line)")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["text", "json", "jsonl"])
+                .default_value("text")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("color")
+                .help("colorize text output (default: auto)")
+                .long("color")
+                .takes_value(true)
+                .possible_values(&["auto", "always", "never"]),
+        )
+        .arg(
+            Arg::with_name("dict")
+                .help(
+                    "directory of the dictionary cache (falls back to a legacy <path>.bincode; \
+                     a path ending in .gz is one gzipped file) (default: ./dict_dump); \
+                     repeat to search several, each labeled in the results",
+                )
+                .long("dict")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .env("EIJIRO_DICT"),
+        )
+        .arg(
+            Arg::with_name("source")
+                .help(
+                    "path to EIJIRO.txt (or EIJIRO.txt.gz), parsed when the cache is missing \
+                     (default: ./EIJIRO.txt); the Nth --source builds the Nth --dict",
+                )
+                .long("source")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .env("EIJIRO_SOURCE"),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .help("print the source line behind each field (rebuilds a cache that lacks them)")
+                .long("raw")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .help("when building the cache, fail on the first source line that cannot be parsed instead of skipping it")
+                .long("strict"),
+        )
+        .arg(
+            Arg::with_name("remote")
+                .help("look words up on an `eijiro serve` instance, e.g. http://host:8080")
+                .long("remote")
+                .takes_value(true)
+                .env("EIJIRO_REMOTE"),
+        )
+        .arg(
+            Arg::with_name("rebuild")
+                .help("re-parse the source even if the cache is up to date")
+                .long("rebuild"),
+        )
+        .arg(
+            Arg::with_name("encoding")
+                .help("text encoding of the source file")
+                .long("encoding")
+                .takes_value(true)
+                .possible_values(&["auto", "utf8", "sjis"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .help("search Japanese explanations for English headwords")
+                .long("reverse")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("prefix")
+                .help("list every headword starting with the word")
+                .long("prefix")
+                .conflicts_with("reverse")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("glob")
+                .help("treat the word as a glob (`*` any run, `?` one character)")
+                .long("glob")
+                .conflicts_with_all(&["reverse", "prefix"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("contains")
+                .help("list every headword containing the word (stops at --limit)")
+                .long("contains")
+                .conflicts_with_all(&["reverse", "prefix", "glob"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("phrase")
+                .help("match headwords ignoring spaces and hyphens (look up, look-up, lookup)")
+                .long("phrase")
+                .conflicts_with_all(&["reverse", "prefix", "glob", "contains"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("regex")
+                .help(
+                    "treat the word as a regex that must match the whole headword, \
+                     e.g. 'colou?r' (stops at --limit)",
+                )
+                .long("regex")
+                .conflicts_with_all(&["reverse", "prefix", "glob", "contains", "phrase"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("in_body")
+                .help(
+                    "list headwords whose definitions contain the word, \
+                     scanning every field (stops at --limit)",
+                )
+                .long("in-body")
+                .conflicts_with_all(&["reverse", "prefix", "glob", "contains", "phrase", "regex"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("pos")
+                .help("only show fields of this part of speech (e.g. 名, {動})")
+                .long("pos")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("max_level")
+                .help("hide fields above this 【レベル】 (fields without a level are kept)")
+                .long("max-level")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<u8>()
+                        .map(|_| ())
+                        .map_err(|_| format!("level must be an integer in 0-255: {}", v))
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .help("order of the matches (default: distance for fuzzy lookups, search order otherwise)")
+                .long("sort")
+                .takes_value(true)
+                .possible_values(&["alpha", "length", "distance", "rank"]),
+        )
+        .arg(
+            Arg::with_name("only_with_examples")
+                .help("only show fields that have example sentences")
+                .long("only-with-examples")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no_fallback")
+                .help("do not retry at distance 1 when an exact lookup finds nothing")
+                .long("no-fallback"),
+        )
+        .arg(
+            Arg::with_name("no_normalize")
+                .help("search the query as typed, without NFKC normalization")
+                .long("no-normalize"),
+        )
+        .arg(
+            Arg::with_name("no_examples")
+                .help("do not print example sentences")
+                .long("no-examples"),
+        )
+        .arg(
+            Arg::with_name("max_examples")
+                .help("print at most this many example sentences per field")
+                .long("max-examples")
+                .takes_value(true)
+                .validator(|v| {
+                    v.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| format!("max-examples must be a non-negative integer: {}", v))
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("fields")
+                .help("print only these comma-separated parts per field, tab-separated: headword, pos, body, complements, pronunciation, examples")
+                .long("fields")
+                .takes_value(true)
+                .validator(|v| Part::parse_list(&v).map(|_| ()))
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no_complements")
+                .help("do not print ◆ complements of explanations")
+                .long("no-complements"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .help("print only the number of matching fields")
+                .long("count"),
+        )
+        .arg(
+            Arg::with_name("id")
+                .help("print the one field with this permalink, e.g. apple#2 (see --show-ids)")
+                .long("id")
+                .takes_value(true)
+                .conflicts_with_all(&["word", "batch"]),
+        )
+        .arg(
+            Arg::with_name("show_ids")
+                .help("prefix each field with its permalink, headword#N or dict/headword#N")
+                .long("show-ids"),
+        )
+        .arg(
+            Arg::with_name("stem")
+                .help("when the word itself is missing, try its base form (running -> run)")
+                .long("stem"),
+        )
+        .arg(
+            Arg::with_name("first")
+                .help("print only the best-ranked headword")
+                .long("first")
+                .conflicts_with_all(&["limit", "offset", "count"]),
+        )
+        .arg(
+            Arg::with_name("follow")
+                .help("also look up the words a field refers to with →, one level deep")
+                .long("follow"),
+        )
+        .arg(
+            Arg::with_name("out")
+                .help("write the results to this file (created or truncated) instead of stdout")
+                .long("out")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("speak")
+                .help("read the matched headword aloud with speak_command from the config (default: say or espeak)")
+                .long("speak"),
+        )
+        .arg(
+            Arg::with_name("mem_report")
+                .help("print the estimated size of the loaded dictionary and exit")
+                .long("mem-report")
+                .conflicts_with("remote"),
+        )
+        .arg(
+            Arg::with_name("no_pager")
+                .help("do not page long results through $PAGER (default: less) on a terminal")
+                .long("no-pager"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .help("print only definitions, without the search header or log messages")
+                .short("q")
+                .long("quiet"),
+        )
+        .arg(
+            Arg::with_name("ignore_case")
+                .help("match headwords case-insensitively")
+                .short("i")
+                .long("ignore-case"),
+        )
+        .subcommand(SubCommand::with_name("stats").about("print dictionary metrics"))
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("dump every entry of the dictionary")
+                .arg(
+                    Arg::with_name("format")
+                        .help("export format: one row per field, or just the headwords")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["tsv", "words"])
+                        .default_value("tsv"),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .help("write to this file instead of stdout")
+                        .long("out")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("answer lookups over HTTP (GET /lookup?word=X&distance=N) for --remote clients")
+                .arg(
+                    Arg::with_name("addr")
+                        .help("address to listen on")
+                        .long("addr")
+                        .takes_value(true)
+                        .default_value("127.0.0.1:8080"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about(
+                    "merge the entries of another source file into the cache \
+                     (lost when the main source changes and the cache is rebuilt)",
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("file in the EIJIRO format to add")
+                        .long("source")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("validate")
+                .about(
+                    "parse a source file and report what it contains, without writing a cache \
+                     (exits with 1 if any line cannot be parsed)",
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("file to check (default: the usual --source)")
+                        .long("source")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("print a shell completion script for eijiro")
+                .arg(
+                    Arg::with_name("shell")
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        );
+    let matches = app.clone().get_matches();
+    if let Some(sub) = matches.subcommand_matches("completions") {
+        // Covers every flag and subcommand defined above.
+        let shell = sub.value_of("shell").unwrap().parse::<Shell>().unwrap();
+        app.gen_completions_to("eijiro", shell, &mut std::io::stdout());
+        return;
+    }
+    if matches.is_present("quiet") {
+        log::set_max_level(log::LevelFilter::Error);
+    }
+    if matches.is_present("gui_flag") && !cfg!(feature = "gui") {
+        error!("This binary was compiled without GUI support; rebuild with `--features gui`");
+        std::process::exit(1);
+    }
+
+    let config = match config_path().map(|path| Config::load(&path)) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+        None => Config::default(),
+    };
+    if let Some(url) = matches.value_of("remote") {
+        if matches.subcommand_name().is_some()
+            || matches.is_present("gui_flag")
+            || matches.is_present("id")
+            || matches.is_present("show_ids")
+        {
+            error!(
+                "--remote only supports lookups without --id or --show-ids; \
+                 run other commands where the dictionary is"
+            );
+            std::process::exit(1);
+        }
+        let remote = Remote::new(url);
+        cli_frontend(matches, &config, Backend::Remote(&remote));
+        return;
+    }
+
+    let dict_path = matches
+        .value_of_os("dict")
+        .map(PathBuf::from)
+        .or_else(|| config.dict_path.clone())
+        .map(|path| expand_path(&path))
+        .unwrap_or_else(|| PathBuf::from("./dict_dump"));
+    let source_path = matches
+        .value_of_os("source")
+        .map(PathBuf::from)
+        .or_else(|| config.source_path.clone())
+        .map(|path| expand_path(&path))
+        .unwrap_or_else(|| PathBuf::from("./EIJIRO.txt"));
+    let encoding = match matches.value_of("encoding") {
+        Some("utf8") => SourceEncoding::Utf8,
+        Some("sjis") => SourceEncoding::ShiftJis,
+        _ => SourceEncoding::Auto,
+    };
+    if let Some(sub) = matches.subcommand_matches("validate") {
+        let path = sub
+            .value_of_os("source")
+            .map(|path| expand_path(Path::new(path)))
+            .unwrap_or(source_path);
+        match validate(&path, encoding) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let rebuild = matches.is_present("rebuild");
+    let strict = matches.is_present("strict");
+    let parse = ParseOptions {
+        keep_raw: matches.is_present("raw"),
+    };
+    let dict = match load_dict(&dict_path, &source_path, encoding, rebuild, parse, strict) {
+        Ok(dict) => dict,
+        Err(e) => {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if matches.is_present("mem_report") {
+        print_mem_report(&dict, output_format(&matches));
+        return;
+    }
+
+    let extra_dicts = matches
+        .values_of_os("dict")
+        .into_iter()
+        .flatten()
+        .skip(1)
+        .collect::<Vec<_>>();
+    if !extra_dicts.is_empty() {
+        if matches.subcommand_name().is_some() || matches.is_present("gui_flag") {
+            error!("Several --dict paths are only supported for lookups");
+            std::process::exit(1);
+        }
+        let mut sources = matches.values_of_os("source").into_iter().flatten().skip(1);
+        let mut layers = vec![Layer::new(&dict_path, dict)];
+        for path in extra_dicts {
+            let path = expand_path(Path::new(path));
+            let source = sources.next().map(|source| expand_path(Path::new(source)));
+            match load_layer(&path, source.as_deref(), encoding, rebuild, parse, strict) {
+                Ok(dict) => layers.push(Layer::new(&path, dict)),
+                Err(e) => {
+                    error!("{:#}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        cli_frontend(matches, &config, Backend::Layered(&layers));
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("serve") {
+        if let Err(e) = serve(&dict, sub) {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+    } else if let Some(sub) = matches.subcommand_matches("stats") {
+        print_stats(&dict, output_format(sub));
+    } else if let Some(sub) = matches.subcommand_matches("export") {
+        if let Err(e) = export(&dict, sub) {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+    } else if let Some(sub) = matches.subcommand_matches("add") {
+        if let Err(e) = add_entries(dict, &dict_path, encoding, sub) {
+            error!("{:#}", e);
+            std::process::exit(1);
+        }
+    } else if matches.is_present("gui_flag") {
+        #[cfg(feature = "gui")]
+        {
+            let initial = matches.values_of("word").into_iter().flatten();
+            if let Err(e) = gui_frontend(dict, initial.collect::<Vec<_>>().join(" ")) {
+                error!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        cli_frontend(matches, &config, Backend::Local(&dict));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eijiro_parser::{Complement, Explanation, PartOfSpeech};
+
+    fn field(ident: Option<&str>, body: &str, complements: &[&str], examples: &[&str]) -> Field {
+        let to_complements = |texts: &[&str]| {
+            texts
+                .iter()
+                .map(|c| Complement {
+                    body: c.to_string(),
+                })
+                .collect::<Vec<_>>()
+        };
+        Field {
+            ident: ident.map(|s| s.to_string()),
+            pos: ident.map(PartOfSpeech::from_ident),
+            level: None,
+            rank: None,
+            explanation: Explanation {
+                body: body.to_string(),
+                complements: to_complements(complements),
+                pronunciation: None,
+                senses: eijiro_parser::split_senses(body),
+            },
+            examples: examples
+                .iter()
+                .map(|e| Example::new(e.to_string(), vec![]))
+                .collect(),
+            see_also: Vec::new(),
+            raw: None,
+        }
+    }
+
+    #[test]
+    fn suggest_nearest() {
+        let dict =
+            eijiro_parser::parse("■apple : りんご\n■apply : 適用する\n■ample : 十分な").unwrap();
+        assert_eq!(suggestions(&dict, "appl", 5), vec!["apple", "apply"]);
+        assert_eq!(suggestions(&dict, "amply", 5), vec!["ample", "apply"]);
+        assert_eq!(suggestions(&dict, "apple", 1).len(), 1);
+        assert!(suggestions(&dict, "banana", 5).is_empty());
+    }
+
+    #[cfg(feature = "gui")]
+    #[test]
+    fn history_order() {
+        let mut history = vec!["run".to_string(), "walk".to_string()];
+        push_history(&mut history, "walk");
+        assert_eq!(history, vec!["walk", "run"]);
+        for i in 0..GUI_HISTORY_LEN {
+            push_history(&mut history, &i.to_string());
+        }
+        assert_eq!(history.len(), GUI_HISTORY_LEN);
+        assert_eq!(history[0], (GUI_HISTORY_LEN - 1).to_string());
+    }
+
+    #[test]
+    fn expand() {
+        let var = |name: &str| match name {
+            "HOME" => Some("/home/me".to_string()),
+            "DICTS" => Some("/srv/dicts".to_string()),
+            _ => None,
+        };
+        assert_eq!(expand_vars("~/EIJIRO.txt", var), "/home/me/EIJIRO.txt");
+        assert_eq!(expand_vars("~", var), "/home/me");
+        assert_eq!(expand_vars("~user/x", var), "~user/x");
+        assert_eq!(expand_vars("$HOME/dict_dump", var), "/home/me/dict_dump");
+        assert_eq!(
+            expand_vars("${DICTS}_old/$DICTS", var),
+            "/srv/dicts_old//srv/dicts"
+        );
+        assert_eq!(expand_vars("$UNSET/a${UNSET}b", var), "$UNSET/a${UNSET}b");
+        assert_eq!(expand_vars("a$/b${c", var), "a$/b${c");
+        assert_eq!(expand_vars("./dict_dump", var), "./dict_dump");
+    }
+
+    #[test]
+    fn stats_examples() {
+        let example = "■・Run. 走れ。";
+        let source = format!(
+            "■a : あ\n■b : い{}\n■c : う{}\n■d : え{}",
+            example,
+            example.repeat(4),
+            example.repeat(11)
+        );
+        let stats = Stats::collect(&eijiro_parser::parse(&source).unwrap());
+        assert_eq!(stats.fields_with_examples, 3);
+        assert_eq!(
+            stats.examples_per_field,
+            ExampleCounts {
+                none: 1,
+                few: 1,
+                some: 1,
+                many: 1,
+            }
+        );
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["examples_per_field"]["11+"], 1);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[test]
+    fn speak_command() {
+        assert_eq!(speak_args("espeak", "run"), vec!["espeak", "run"]);
+        assert_eq!(
+            speak_args("espeak -v en-us {word}", "look up"),
+            vec!["espeak", "-v", "en-us", "look up"]
+        );
+        assert!(speak_args("  ", "run").is_empty());
+    }
+
+    #[test]
+    fn sort_order() {
+        let results = || {
+            ["runner", "run", "ran", "rune"]
+                .iter()
+                .map(|w| (w.to_string(), ()))
+                .collect::<Vec<_>>()
+        };
+        let sorted = |order| {
+            sort_results(order, "run", results())
+                .into_iter()
+                .map(|(w, _)| w)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(SortOrder::Alpha), ["ran", "run", "rune", "runner"]);
+        assert_eq!(sorted(SortOrder::Length), ["run", "ran", "rune", "runner"]);
+        assert_eq!(
+            sorted(SortOrder::Distance),
+            ["run", "rune", "ran", "runner"]
+        );
+    }
+
+    #[test]
+    fn sort_rank() {
+        let ranked = |body: &str, rank| Field {
+            rank,
+            ..field(None, body, &[], &[])
+        };
+        let results: Matches = vec![
+            ("ran".to_string(), vec![Cow::Owned(ranked("a", None))]),
+            (
+                "run".to_string(),
+                vec![
+                    Cow::Owned(ranked("b", None)),
+                    Cow::Owned(ranked("c", Some(30))),
+                    Cow::Owned(ranked("d", Some(5))),
+                ],
+            ),
+            ("rune".to_string(), vec![Cow::Owned(ranked("e", Some(10)))]),
+            ("runner".to_string(), vec![Cow::Owned(ranked("f", None))]),
+        ];
+        let sorted = sort_by_rank(results)
+            .into_iter()
+            .map(|(w, fields)| {
+                let bodies = fields.iter().map(|f| f.explanation.body.clone());
+                (w, bodies.collect::<Vec<_>>().join(""))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sorted,
+            [("run", "dcb"), ("rune", "e"), ("ran", "a"), ("runner", "f")]
+                .iter()
+                .map(|(w, b)| (w.to_string(), b.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rank_fuzzy() {
+        let ranked = |query, words: &[&str]| {
+            let results = words.iter().map(|w| (w.to_string(), ())).collect();
+            rank_matches(query, results)
+                .into_iter()
+                .map(|(w, _)| w)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ranked("good", &["food", "gold", "good", "goods", "goof", "wood"]),
+            ["good", "goods", "goof", "gold", "food", "wood"]
+        );
+        // An inflection that extends the query beats a same-length typo.
+        assert_eq!(ranked("walk", &["talk", "walks"]), ["walks", "talk"]);
+        // One edit per Japanese character, not per UTF-8 byte.
+        assert_eq!(ranked("走る", &["走った", "走れ"]), ["走れ", "走った"]);
+        assert_eq!(match_score("run", "run"), 0);
+        assert_eq!(
+            match_score("good", "goods"),
+            RANK_DISTANCE_WEIGHT + RANK_LENGTH_WEIGHT
+        );
+    }
+
+    #[test]
+    fn query_params() {
+        let query = Query {
+            word: "look up",
+            mode: SearchMode::Contains,
+            distance: 1,
+            ignore_case: true,
+            cap: Some(21),
+        };
+        let params = query
+            .params()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(Query::from_params(&params), Ok(query));
+
+        let mut params = HashMap::new();
+        assert!(Query::from_params(&params).is_err());
+        params.insert("word".to_string(), "run".to_string());
+        assert_eq!(Query::from_params(&params).unwrap().mode, SearchMode::Fuzzy);
+        params.insert("mode".to_string(), "sideways".to_string());
+        assert!(Query::from_params(&params).is_err());
+        params.remove("mode");
+        params.insert("distance".to_string(), "4294967297".to_string());
+        assert_eq!(
+            Query::from_params(&params),
+            Err("distance must be a non-negative integer: 4294967297".to_string())
+        );
+    }
+
+    #[test]
+    fn serve_lookup() {
+        let dict = eijiro_parser::parse("■run : 走る\n■rum : ラム酒\n■walk : 歩く").unwrap();
+        let body = respond(&dict, "/lookup?word=run&distance=1").unwrap();
+        let entries: Vec<RemoteEntry> = serde_json::from_str(&body).unwrap();
+        let words = entries.iter().map(|e| e.word.as_ref()).collect::<Vec<_>>();
+        assert_eq!(words, ["rum", "run"]);
+        assert_eq!(entries[1].fields[0].explanation.body, "走る");
+
+        let body = respond(&dict, "/lookup?word=%E6%AD%A9%E3%81%8F&mode=reverse").unwrap();
+        assert!(body.contains("walk"));
+        assert_eq!(respond(&dict, "/lookup?distance=1").unwrap_err().0, 400);
+        assert_eq!(respond(&dict, "/other").unwrap_err().0, 404);
+    }
+
+    fn text_opts() -> LookupOptions {
+        LookupOptions {
+            mode: SearchMode::Fuzzy,
+            distance: 0,
+            format: OutputFormat::Text,
+            limit: None,
+            offset: 0,
+            pos: None,
+            max_level: None,
+            only_with_examples: false,
+            sort: None,
+            ignore_case: false,
+            count: false,
+            quiet: true,
+            fallback: false,
+            normalize: false,
+            show_ids: true,
+            stem: false,
+            first: false,
+            follow: false,
+            speak: None,
+            print: PrintOptions::plain(),
+        }
+    }
+
+    #[test]
+    fn layered_ids() {
+        let layer = |label: &str, source: &str| Layer {
+            label: label.to_string(),
+            dict: eijiro_parser::parse(source).unwrap(),
+        };
+        let layers = [
+            layer("main", "■run : 走る"),
+            layer("personal", "■run : 駆ける\n■walk : 歩く"),
+        ];
+        let backend = Backend::Layered(&layers);
+        let opts = text_opts();
+        let output = |f: &dyn Fn(&mut dyn Write) -> usize| {
+            let mut out = Vec::new();
+            let matched = f(&mut out);
+            (matched, String::from_utf8(out).unwrap())
+        };
+
+        let (_, text) = output(&|out| lookup_word("run", backend, &opts, out));
+        assert!(text.contains("[main/run#1] 走る"));
+        assert!(text.contains("[personal/run#1] 駆ける"));
+
+        let (matched, text) = output(&|out| lookup_id("personal/run#1", backend, &opts, out));
+        assert_eq!(matched, 1);
+        assert_eq!(text, "run [personal]\n[personal/run#1] 駆ける\n");
+        let (_, text) = output(&|out| lookup_id("run#1", backend, &opts, out));
+        assert!(text.contains("走る"));
+        let (_, text) = output(&|out| lookup_id("walk#1", backend, &opts, out));
+        assert!(text.contains("[personal/walk#1] 歩く"));
+        assert_eq!(
+            output(&|out| lookup_id("main/walk#1", backend, &opts, out)).0,
+            0
+        );
+    }
+
+    #[test]
+    fn stream_jsonl() {
+        let dict =
+            eijiro_parser::parse("■run : 走る\n■runner : 走者\n■running : 走ること\n■walk : 歩く")
+                .unwrap();
+        let opts = LookupOptions {
+            mode: SearchMode::Prefix,
+            format: OutputFormat::JsonLines,
+            show_ids: false,
+            offset: 1,
+            limit: Some(1),
+            ..text_opts()
+        };
+        assert!(opts.streams());
+        assert!(!LookupOptions {
+            first: true,
+            ..opts.clone()
+        }
+        .streams());
+        let mut out = Vec::new();
+        assert_eq!(
+            lookup_word("run", Backend::Local(&dict), &opts, &mut out),
+            2
+        );
+        let lines = String::from_utf8(out).unwrap();
+        let entries: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["word"], "runner");
+    }
+
+    #[test]
+    fn stale_cache() {
+        let stamp = |modified, len| SourceStamp { modified, len };
+        assert!(!cache_is_stale(Some(stamp(10, 5)), stamp(10, 5)));
+        assert!(!cache_is_stale(Some(stamp(10, 5)), stamp(9, 5)));
+        assert!(cache_is_stale(Some(stamp(10, 5)), stamp(11, 5)));
+        assert!(cache_is_stale(Some(stamp(10, 5)), stamp(10, 6)));
+        assert!(!cache_is_stale(None, stamp(10, 5)));
+    }
+
+    #[test]
+    fn stale_rebuild() {
+        let dir = std::env::temp_dir().join(format!("eijiro-stale-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, cache) = (dir.join("EIJIRO.txt"), dir.join("dict_dump"));
+        let load = || {
+            let parse = ParseOptions::default();
+            load_dict(&cache, &source, SourceEncoding::Utf8, false, parse, true).unwrap()
+        };
+        std::fs::write(&source, "■run : 走る").unwrap();
+        load();
+        let mapped = load();
+        // The rebuild replaces the cache `mapped` reads from.
+        std::fs::write(&source, "■run : 走る\n■walk : 歩く").unwrap();
+        assert!(load().contains("walk"));
+        assert_eq!(mapped.get("run").unwrap()[0].explanation.body, "走る");
+        assert!(!mapped.contains("walk"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn printer_plain() {
+        let f = field(None, "走る", &[], &[]);
+        assert_eq!(printer("run", &f, &PrintOptions::plain()), "走る");
+    }
+
+    #[test]
+    fn printer_ident() {
+        let f = field(Some("動"), "走る", &[], &[]);
+        assert_eq!(printer("run", &f, &PrintOptions::plain()), "{動} : 走る");
+    }
+
+    #[test]
+    fn printer_senses() {
+        let f = field(Some("動"), "①走る②逃げる", &["ランニング"], &[]);
+        assert_eq!(
+            printer("run", &f, &PrintOptions::plain()),
+            "{動} :\n    ①走る\n    ②逃げる◆ランニング"
+        );
+    }
+
+    #[test]
+    fn printer_complements() {
+        let f = field(Some("名"), "走ること", &["ランニング", "競走"], &[]);
+        assert_eq!(
+            printer("run", &f, &PrintOptions::plain()),
+            "{名} : 走ること◆ランニング◆競走"
+        );
+    }
+
+    #[test]
+    fn printer_see_also() {
+        let mut f = field(None, "あきらめる", &[], &["I gave up."]);
+        f.see_also = vec!["give in".to_string(), "surrender".to_string()];
+        assert_eq!(
+            printer("give up", &f, &PrintOptions::plain()),
+            "あきらめる\n    → give in, surrender\n        I gave up."
+        );
+    }
+
+    #[test]
+    fn printer_examples() {
+        let mut f = field(
+            Some("動"),
+            "走る",
+            &["急ぐ"],
+            &["・I run every day. 私は毎日走る。", "Run!"],
+        );
+        f.explanation.pronunciation = Some("rʌ́n".to_string());
+        assert_eq!(
+            printer("run", &f, &PrintOptions::plain()),
+            "{動} : 走る◆急ぐ\n    【発音】rʌ́n\n        I run every day.\n        私は毎日走る。\n        Run!"
+        );
+    }
+
+    #[test]
+    fn printer_omissions() {
+        let f = field(Some("動"), "走る", &["急ぐ"], &["Run!"]);
+        let print = PrintOptions {
+            examples: false,
+            complements: false,
+            ..PrintOptions::plain()
+        };
+        assert_eq!(printer("run", &f, &print), "{動} : 走る");
+    }
+
+    #[test]
+    fn printer_fields() {
+        let f = field(Some("動"), "走る", &["ランニング"], &["Run!", "Run away."]);
+        let print = |list| PrintOptions {
+            max_examples: Some(1),
+            parts: Some(Part::parse_list(list).unwrap()),
+            ..PrintOptions::plain()
+        };
+        assert_eq!(printer("run", &f, &print("headword,examples")), "run\tRun!");
+        assert_eq!(
+            printer("run", &f, &print("body, pos,complements")),
+            "走る\t動\t◆ランニング"
+        );
+        assert!(Part::parse_list("headword,sense").is_err());
+    }
+
+    #[test]
+    fn printer_max_examples() {
+        let f = field(None, "走る", &[], &["Run!", "Ran.", "Running."]);
+        let print = |max| PrintOptions {
+            max_examples: Some(max),
+            ..PrintOptions::plain()
+        };
+        assert_eq!(
+            printer("run", &f, &print(1)),
+            "走る\n        Run!\n        (+2 more examples)"
+        );
+        assert_eq!(printer("run", &f, &print(0)), "走る");
+        assert_eq!(
+            printer("run", &f, &print(3)),
+            printer("run", &f, &PrintOptions::plain())
+        );
     }
 }